@@ -1,7 +1,8 @@
-use blaze_turbo::{KvStore, KvsEngine, SledKvsEngine};
+use blaze_turbo::{KvStore, KvStoreOptions, KvsEngine, SledKvsEngine, SyncPolicy};
 use criterion::BatchSize::SmallInput;
-use criterion::{criterion_group, criterion_main, Criterion};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
 use rand::prelude::*;
+use std::thread;
 use tempfile::TempDir;
 
 fn write_benchmark(c: &mut Criterion) {
@@ -109,5 +110,54 @@ fn read_benchmark(c: &mut Criterion) {
     group.finish()
 }
 
-criterion_group!(benches, write_benchmark, read_benchmark);
+fn group_commit_benchmark(c: &mut Criterion) {
+    let mut group = c.benchmark_group("concurrent_write");
+    group.sample_size(10);
+    let threads = 8;
+    let writes_per_thread = 100;
+    for group_commit in [false, true] {
+        let name = if group_commit { "group" } else { "mutex" };
+        group.bench_function(BenchmarkId::new(name, threads), |b| {
+            b.iter_batched(
+                || {
+                    let temp_dir =
+                        TempDir::new().expect("unable to create temporary working directory");
+                    // sync every write (or group), which is where grouping pays off
+                    let options = KvStoreOptions::new()
+                        .sync_policy(SyncPolicy::EveryWrite)
+                        .group_commit(group_commit);
+                    let store = KvStore::open_with(temp_dir.path(), options)
+                        .expect("unable to init KvStore");
+                    (temp_dir, store)
+                },
+                |(_temp_dir, store)| {
+                    let handles: Vec<_> = (0..threads)
+                        .map(|t| {
+                            let store = store.clone();
+                            thread::spawn(move || {
+                                for i in 0..writes_per_thread {
+                                    store
+                                        .set(format!("key{}_{}", t, i), format!("value{}", i))
+                                        .expect("unable to write KvStore");
+                                }
+                            })
+                        })
+                        .collect();
+                    for handle in handles {
+                        handle.join().unwrap();
+                    }
+                },
+                SmallInput,
+            )
+        });
+    }
+    group.finish()
+}
+
+criterion_group!(
+    benches,
+    write_benchmark,
+    read_benchmark,
+    group_commit_benchmark
+);
 criterion_main!(benches);
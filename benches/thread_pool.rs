@@ -3,11 +3,15 @@ use blaze_turbo::{RayonThreadPool, SharedQueueThreadPool, ThreadPool};
 use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
 use crossbeam_utils::sync::WaitGroup;
 use log::{warn, LevelFilter};
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{Arc, Once};
+use std::sync::Once;
 use std::thread;
 use std::time::Duration;
 use tempfile::TempDir;
+#[cfg(feature = "async")]
+use {
+    blaze_turbo::{AsyncClient, AsyncKvServer},
+    tokio::task::JoinSet,
+};
 
 const ENTRY_COUNT: usize = 100;
 const THREAD_COUNT: [usize; 4] = [1, 2, 4, 8];
@@ -26,12 +30,11 @@ fn write_queued_kvstore(c: &mut Criterion) {
             let eng = KvStore::open(dir.path()).unwrap();
             let server_pool = SharedQueueThreadPool::new(size).unwrap();
 
-            let is_stop = Arc::new(AtomicBool::new(false));
-
-            let mut server = KvServer::new(eng, server_pool, Arc::clone(&is_stop));
+            let mut server = KvServer::new(eng, server_pool);
+            let shutdown = server.shutdown_handle();
 
             let handle = thread::spawn(move || {
-                server.serve(&addr.to_owned()).unwrap();
+                server.serve(addr).unwrap();
             });
 
             let value = "value".to_owned();
@@ -42,14 +45,16 @@ fn write_queued_kvstore(c: &mut Criterion) {
 
             b.iter(|| {
                 let wg = WaitGroup::new();
-                for i in 0..ENTRY_COUNT {
-                    let key = keys[i].clone();
+                for key in &keys {
+                    let key = key.clone();
                     let value = value.clone();
                     let wg = wg.clone();
                     client_pool.spawn(move || {
                         match Client::new(addr) {
                             Ok(mut client) => {
-                                if let Err(err) = client.request(&Request::SET(key, value)) {
+                                if let Err(err) = client
+                                    .request(&Request::SET(key.into_bytes(), value.into_bytes()))
+                                {
                                     warn!("request failed because {:?}", err);
                                 }
                             }
@@ -63,9 +68,7 @@ fn write_queued_kvstore(c: &mut Criterion) {
                 wg.wait();
             });
 
-            is_stop.store(true, Ordering::SeqCst);
-
-            let _ = Client::new(addr);
+            shutdown.shutdown();
 
             if let Err(err) = handle.join() {
                 warn!("exit server failed because {:?}", err);
@@ -88,12 +91,11 @@ fn read_queued_kvstore(c: &mut Criterion) {
             let eng = KvStore::open(dir.path()).unwrap();
             let server_pool = SharedQueueThreadPool::new(size).unwrap();
 
-            let is_stop = Arc::new(AtomicBool::new(false));
-
-            let mut server = KvServer::new(eng, server_pool, Arc::clone(&is_stop));
+            let mut server = KvServer::new(eng, server_pool);
+            let shutdown = server.shutdown_handle();
 
             let handle = thread::spawn(move || {
-                server.serve(&addr.to_owned()).unwrap();
+                server.serve(addr).unwrap();
             });
 
             let value = "value".to_owned();
@@ -102,22 +104,24 @@ fn read_queued_kvstore(c: &mut Criterion) {
 
             thread::sleep(Duration::from_secs(1));
 
-            for i in 0..ENTRY_COUNT {
+            for key in &keys {
                 let mut write_client = Client::new(addr).unwrap();
-                let key = keys[i].clone();
+                let key = key.clone();
                 let value = value.clone();
-                write_client.request(&Request::SET(key, value)).unwrap();
+                write_client
+                    .request(&Request::SET(key.into_bytes(), value.into_bytes()))
+                    .unwrap();
             }
 
             b.iter(|| {
                 let wg = WaitGroup::new();
-                for i in 0..ENTRY_COUNT {
-                    let key = keys[i].clone();
+                for key in &keys {
+                    let key = key.clone();
                     let wg = wg.clone();
                     client_pool.spawn(move || {
                         match Client::new(addr) {
                             Ok(mut client) => {
-                                if let Err(err) = client.request(&Request::GET(key)) {
+                                if let Err(err) = client.request(&Request::GET(key.into_bytes())) {
                                     warn!("request failed because {:?}", err);
                                 }
                             }
@@ -132,9 +136,7 @@ fn read_queued_kvstore(c: &mut Criterion) {
                 wg.wait();
             });
 
-            is_stop.store(true, Ordering::SeqCst);
-
-            let _ = Client::new(addr);
+            shutdown.shutdown();
 
             if let Err(err) = handle.join() {
                 warn!("exit server failed because {:?}", err);
@@ -157,12 +159,11 @@ fn write_rayon_kvstore(c: &mut Criterion) {
             let eng = KvStore::open(dir.path()).unwrap();
             let server_pool = RayonThreadPool::new(size).unwrap();
 
-            let is_stop = Arc::new(AtomicBool::new(false));
-
-            let mut server = KvServer::new(eng, server_pool, Arc::clone(&is_stop));
+            let mut server = KvServer::new(eng, server_pool);
+            let shutdown = server.shutdown_handle();
 
             let handle = thread::spawn(move || {
-                server.serve(&addr.to_owned()).unwrap();
+                server.serve(addr).unwrap();
             });
 
             let value = "value".to_owned();
@@ -173,14 +174,16 @@ fn write_rayon_kvstore(c: &mut Criterion) {
 
             b.iter(|| {
                 let wg = WaitGroup::new();
-                for i in 0..ENTRY_COUNT {
-                    let key = keys[i].clone();
+                for key in &keys {
+                    let key = key.clone();
                     let value = value.clone();
                     let wg = wg.clone();
                     client_pool.spawn(move || {
                         match Client::new(addr) {
                             Ok(mut client) => {
-                                if let Err(err) = client.request(&Request::SET(key, value)) {
+                                if let Err(err) = client
+                                    .request(&Request::SET(key.into_bytes(), value.into_bytes()))
+                                {
                                     warn!("request failed because {:?}", err);
                                 }
                             }
@@ -194,9 +197,7 @@ fn write_rayon_kvstore(c: &mut Criterion) {
                 wg.wait();
             });
 
-            is_stop.store(true, Ordering::SeqCst);
-
-            let _ = Client::new(addr);
+            shutdown.shutdown();
 
             if let Err(err) = handle.join() {
                 warn!("exit server failed because {:?}", err);
@@ -219,12 +220,11 @@ fn read_rayon_kvstore(c: &mut Criterion) {
             let eng = KvStore::open(dir.path()).unwrap();
             let server_pool = RayonThreadPool::new(size).unwrap();
 
-            let is_stop = Arc::new(AtomicBool::new(false));
-
-            let mut server = KvServer::new(eng, server_pool, Arc::clone(&is_stop));
+            let mut server = KvServer::new(eng, server_pool);
+            let shutdown = server.shutdown_handle();
 
             let handle = thread::spawn(move || {
-                server.serve(&addr.to_owned()).unwrap();
+                server.serve(addr).unwrap();
             });
 
             let value = "value".to_owned();
@@ -233,22 +233,24 @@ fn read_rayon_kvstore(c: &mut Criterion) {
 
             thread::sleep(Duration::from_secs(1));
 
-            for i in 0..ENTRY_COUNT {
+            for key in &keys {
                 let mut write_client = Client::new(addr).unwrap();
-                let key = keys[i].clone();
+                let key = key.clone();
                 let value = value.clone();
-                write_client.request(&Request::SET(key, value)).unwrap();
+                write_client
+                    .request(&Request::SET(key.into_bytes(), value.into_bytes()))
+                    .unwrap();
             }
 
             b.iter(|| {
                 let wg = WaitGroup::new();
-                for i in 0..ENTRY_COUNT {
-                    let key = keys[i].clone();
+                for key in &keys {
+                    let key = key.clone();
                     let wg = wg.clone();
                     client_pool.spawn(move || {
                         match Client::new(addr) {
                             Ok(mut client) => {
-                                if let Err(err) = client.request(&Request::GET(key)) {
+                                if let Err(err) = client.request(&Request::GET(key.into_bytes())) {
                                     warn!("request failed because {:?}", err);
                                 }
                             }
@@ -263,9 +265,7 @@ fn read_rayon_kvstore(c: &mut Criterion) {
                 wg.wait();
             });
 
-            is_stop.store(true, Ordering::SeqCst);
-
-            let _ = Client::new(addr);
+            shutdown.shutdown();
 
             if let Err(err) = handle.join() {
                 warn!("exit server failed because {:?}", err);
@@ -288,12 +288,11 @@ fn write_rayon_sledkvengine(c: &mut Criterion) {
             let eng = SledKvsEngine::open(dir.path()).unwrap();
             let server_pool = RayonThreadPool::new(size).unwrap();
 
-            let is_stop = Arc::new(AtomicBool::new(false));
-
-            let mut server = KvServer::new(eng, server_pool, Arc::clone(&is_stop));
+            let mut server = KvServer::new(eng, server_pool);
+            let shutdown = server.shutdown_handle();
 
             let handle = thread::spawn(move || {
-                server.serve(&addr.to_owned()).unwrap();
+                server.serve(addr).unwrap();
             });
 
             let value = "value".to_owned();
@@ -304,14 +303,16 @@ fn write_rayon_sledkvengine(c: &mut Criterion) {
 
             b.iter(|| {
                 let wg = WaitGroup::new();
-                for i in 0..ENTRY_COUNT {
-                    let key = keys[i].clone();
+                for key in &keys {
+                    let key = key.clone();
                     let value = value.clone();
                     let wg = wg.clone();
                     client_pool.spawn(move || {
                         match Client::new(addr) {
                             Ok(mut client) => {
-                                if let Err(err) = client.request(&Request::SET(key, value)) {
+                                if let Err(err) = client
+                                    .request(&Request::SET(key.into_bytes(), value.into_bytes()))
+                                {
                                     warn!("request failed because {:?}", err);
                                 }
                             }
@@ -325,9 +326,7 @@ fn write_rayon_sledkvengine(c: &mut Criterion) {
                 wg.wait();
             });
 
-            is_stop.store(true, Ordering::SeqCst);
-
-            let _ = Client::new(addr);
+            shutdown.shutdown();
 
             if let Err(err) = handle.join() {
                 warn!("exit server failed because {:?}", err);
@@ -350,12 +349,11 @@ fn read_rayon_sledkvengine(c: &mut Criterion) {
             let eng = SledKvsEngine::open(dir.path()).unwrap();
             let server_pool = RayonThreadPool::new(size).unwrap();
 
-            let is_stop = Arc::new(AtomicBool::new(false));
-
-            let mut server = KvServer::new(eng, server_pool, Arc::clone(&is_stop));
+            let mut server = KvServer::new(eng, server_pool);
+            let shutdown = server.shutdown_handle();
 
             let handle = thread::spawn(move || {
-                server.serve(&addr.to_owned()).unwrap();
+                server.serve(addr).unwrap();
             });
 
             let value = "value".to_owned();
@@ -364,22 +362,24 @@ fn read_rayon_sledkvengine(c: &mut Criterion) {
 
             thread::sleep(Duration::from_secs(1));
 
-            for i in 0..ENTRY_COUNT {
+            for key in &keys {
                 let mut write_client = Client::new(addr).unwrap();
-                let key = keys[i].clone();
+                let key = key.clone();
                 let value = value.clone();
-                write_client.request(&Request::SET(key, value)).unwrap();
+                write_client
+                    .request(&Request::SET(key.into_bytes(), value.into_bytes()))
+                    .unwrap();
             }
 
             b.iter(|| {
                 let wg = WaitGroup::new();
-                for i in 0..ENTRY_COUNT {
-                    let key = keys[i].clone();
+                for key in &keys {
+                    let key = key.clone();
                     let wg = wg.clone();
                     client_pool.spawn(move || {
                         match Client::new(addr) {
                             Ok(mut client) => {
-                                if let Err(err) = client.request(&Request::GET(key)) {
+                                if let Err(err) = client.request(&Request::GET(key.into_bytes())) {
                                     warn!("request failed because {:?}", err);
                                 }
                             }
@@ -394,9 +394,7 @@ fn read_rayon_sledkvengine(c: &mut Criterion) {
                 wg.wait();
             });
 
-            is_stop.store(true, Ordering::SeqCst);
-
-            let _ = Client::new(addr);
+            shutdown.shutdown();
 
             if let Err(err) = handle.join() {
                 warn!("exit server failed because {:?}", err);
@@ -406,9 +404,152 @@ fn read_rayon_sledkvengine(c: &mut Criterion) {
     group.finish();
 }
 
+#[cfg(feature = "async")]
+fn async_runtime(worker_threads: usize) -> tokio::runtime::Runtime {
+    tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(worker_threads)
+        .enable_all()
+        .build()
+        .unwrap()
+}
+
+#[cfg(feature = "async")]
+fn write_async_kvstore(c: &mut Criterion) {
+    START.call_once(|| {
+        env_logger::builder().filter_level(LevelFilter::Info).init();
+    });
+    let mut group = c.benchmark_group("write_async_kvstore");
+    for size in THREAD_COUNT.iter() {
+        group.bench_with_input(BenchmarkId::from_parameter(size), size, |b, &size| {
+            let addr = "127.0.0.1:4001";
+
+            let dir = TempDir::new().unwrap();
+            let eng = KvStore::open(dir.path()).unwrap();
+            let server_runtime = async_runtime(size);
+
+            let server = AsyncKvServer::new(eng);
+            let shutdown = server.shutdown_handle();
+
+            let handle = server_runtime.spawn(async move { server.serve(addr).await.unwrap() });
+
+            let keys: Vec<String> = (0..ENTRY_COUNT).map(|x| format!("key{}", x)).collect();
+            let client_runtime = async_runtime(THREAD_COUNT[THREAD_COUNT.len() - 1]);
+
+            thread::sleep(Duration::from_secs(1));
+
+            b.iter(|| {
+                client_runtime.block_on(async {
+                    let mut requests = JoinSet::new();
+                    for key in &keys {
+                        let key = key.clone();
+                        requests.spawn(async move {
+                            match AsyncClient::new(addr).await {
+                                Ok(mut client) => {
+                                    if let Err(err) = client
+                                        .request(&Request::SET(key.into_bytes(), b"value".to_vec()))
+                                        .await
+                                    {
+                                        warn!("request failed because {:?}", err);
+                                    }
+                                }
+                                Err(err) => {
+                                    warn!("init client failed because {:?}", err);
+                                }
+                            };
+                        });
+                    }
+                    while requests.join_next().await.is_some() {}
+                });
+            });
+
+            shutdown.shutdown();
+
+            if let Err(err) = server_runtime.block_on(handle) {
+                warn!("exit server failed because {:?}", err);
+            }
+        });
+    }
+    group.finish();
+}
+
+#[cfg(feature = "async")]
+fn read_async_kvstore(c: &mut Criterion) {
+    START.call_once(|| {
+        env_logger::builder().filter_level(LevelFilter::Info).init();
+    });
+    let mut group = c.benchmark_group("read_async_kvstore");
+    for size in THREAD_COUNT.iter() {
+        group.bench_with_input(BenchmarkId::from_parameter(size), size, |b, &size| {
+            let addr = "127.0.0.1:4001";
+
+            let dir = TempDir::new().unwrap();
+            let eng = KvStore::open(dir.path()).unwrap();
+            let server_runtime = async_runtime(size);
+
+            let server = AsyncKvServer::new(eng);
+            let shutdown = server.shutdown_handle();
+
+            let handle = server_runtime.spawn(async move { server.serve(addr).await.unwrap() });
+
+            let keys: Vec<String> = (0..ENTRY_COUNT).map(|x| format!("key{}", x)).collect();
+            let client_runtime = async_runtime(THREAD_COUNT[THREAD_COUNT.len() - 1]);
+
+            thread::sleep(Duration::from_secs(1));
+
+            client_runtime.block_on(async {
+                let mut write_client = AsyncClient::new(addr).await.unwrap();
+                for key in &keys {
+                    write_client
+                        .request(&Request::SET(key.clone().into_bytes(), b"value".to_vec()))
+                        .await
+                        .unwrap();
+                }
+            });
+
+            b.iter(|| {
+                client_runtime.block_on(async {
+                    let mut requests = JoinSet::new();
+                    for key in &keys {
+                        let key = key.clone();
+                        requests.spawn(async move {
+                            match AsyncClient::new(addr).await {
+                                Ok(mut client) => {
+                                    if let Err(err) =
+                                        client.request(&Request::GET(key.into_bytes())).await
+                                    {
+                                        warn!("request failed because {:?}", err);
+                                    }
+                                }
+                                Err(err) => {
+                                    warn!("init client failed because {:?}", err);
+                                }
+                            };
+                        });
+                    }
+                    while requests.join_next().await.is_some() {}
+                });
+            });
+
+            shutdown.shutdown();
+
+            if let Err(err) = server_runtime.block_on(handle) {
+                warn!("exit server failed because {:?}", err);
+            }
+        });
+    }
+    group.finish();
+}
+
+#[cfg(not(feature = "async"))]
 criterion_group! {
     name = benches;
     config = Criterion::default().sample_size(10);
     targets = write_queued_kvstore, read_queued_kvstore, write_rayon_kvstore, read_rayon_kvstore, write_rayon_sledkvengine, read_rayon_sledkvengine
 }
+#[cfg(feature = "async")]
+criterion_group! {
+    name = benches;
+    config = Criterion::default().sample_size(10);
+    targets = write_queued_kvstore, read_queued_kvstore, write_rayon_kvstore, read_rayon_kvstore, write_rayon_sledkvengine, read_rayon_sledkvengine, write_async_kvstore, read_async_kvstore
+}
 criterion_main!(benches);
@@ -0,0 +1,81 @@
+use crate::client::{closed_by_server, request_result, scan_result};
+use crate::common::prefix_end;
+use crate::proto::AsyncConnection;
+use crate::{KvPairs, Protocol, Request, Response, Result};
+use tokio::net::TcpStream;
+
+/** A client like `Client` whose requests are futures, to be used on a tokio runtime.
+# Example
+```no_run
+use blaze_turbo::{AsyncClient, Request, Result};
+# async fn try_main() -> Result<()> {
+let mut client = AsyncClient::new("127.0.0.1:4000").await?;
+client.request(&Request::SET(b"1".to_vec(), b"1".to_vec())).await?;
+assert_eq!(client.request(&Request::GET(b"1".to_vec())).await?, Some(b"1".to_vec()));
+# Ok(())
+# }
+```
+ */
+pub struct AsyncClient {
+    connection: AsyncConnection<TcpStream>,
+}
+
+impl AsyncClient {
+    /// init a client which speaks the binary protocol
+    pub async fn new(addr: &str) -> Result<AsyncClient> {
+        AsyncClient::connect(addr, Protocol::Binary).await
+    }
+
+    /// init a client which speaks the given protocol
+    pub async fn connect(addr: &str, protocol: Protocol) -> Result<AsyncClient> {
+        let stream = TcpStream::connect(addr).await?;
+        Ok(AsyncClient {
+            connection: AsyncConnection::connect(stream, protocol).await?,
+        })
+    }
+
+    /// authenticate the connection, so later requests get the permissions of the user
+    pub async fn authenticate(&mut self, user: &str, password: &str) -> Result<()> {
+        self.request(&Request::AUTH(user.to_owned(), password.to_owned()))
+            .await?;
+        Ok(())
+    }
+
+    /// perform a request
+    pub async fn request(&mut self, request: &Request) -> Result<Option<Vec<u8>>> {
+        self.connection.write(request)?;
+        self.connection.flush().await?;
+        request_result(self.read_message().await?)
+    }
+
+    /// return up to `limit` key/value pairs with `start <= key < end`, in key order
+    pub async fn scan(
+        &mut self,
+        start: impl Into<Vec<u8>>,
+        end: Option<Vec<u8>>,
+        limit: Option<usize>,
+    ) -> Result<KvPairs> {
+        self.connection
+            .write(&Request::SCAN(start.into(), end, limit))?;
+        self.connection.flush().await?;
+        scan_result(self.read_message().await?)
+    }
+
+    /// return up to `limit` key/value pairs whose key starts with `prefix`, in key order
+    pub async fn scan_prefix(
+        &mut self,
+        prefix: impl Into<Vec<u8>>,
+        limit: Option<usize>,
+    ) -> Result<KvPairs> {
+        let prefix = prefix.into();
+        let end = prefix_end(&prefix);
+        self.scan(prefix, end, limit).await
+    }
+
+    async fn read_message(&mut self) -> Result<Response> {
+        self.connection
+            .read::<Response>()
+            .await?
+            .ok_or_else(closed_by_server)
+    }
+}
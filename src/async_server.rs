@@ -0,0 +1,129 @@
+use crate::proto::AsyncConnection;
+use crate::server::{log_request, process_request, DRAIN_TIMEOUT, IDLE_TIMEOUT};
+use crate::{KVStoreError, KvsEngine, Request, Result, ShutdownHandle};
+use log::{debug, error, info, warn};
+use std::time::SystemTime;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+use tokio::task::{self, JoinError, JoinSet};
+use tokio::time;
+
+/// a KvServer which serves every connection as a task on the tokio runtime running it, and
+/// calls the engine on tokio's blocking threads so the reactor is never blocked. It refuses
+/// backups, having no directory to write them to.
+pub struct AsyncKvServer<E: KvsEngine> {
+    engine: E,
+    shutdown: ShutdownHandle,
+}
+
+impl<E: KvsEngine> AsyncKvServer<E> {
+    /// create server with engine
+    pub fn new(engine: E) -> Self {
+        AsyncKvServer {
+            engine,
+            shutdown: ShutdownHandle::default(),
+        }
+    }
+
+    /// Return a handle which shuts the server down from another thread or task.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Serve at addr to handle requests until a shutdown is requested through a
+    /// `ShutdownHandle`. Then stop accepting, let open connections finish their current
+    /// request, waiting for them for a while, and flush the engine before returning.
+    pub async fn serve(self, addr: &str) -> Result<()> {
+        let listener = TcpListener::bind(addr).await?;
+        let (stop, _) = watch::channel(false);
+        let mut connections = JoinSet::new();
+        let shutdown = self.shutdown.notify.notified();
+        tokio::pin!(shutdown);
+        // registered before the flag is checked, so a shutdown in between is not missed
+        shutdown.as_mut().enable();
+        while !self.shutdown.is_shutdown() {
+            tokio::select! {
+                accepted = listener.accept() => match accepted {
+                    Ok((stream, _)) => {
+                        let engine = self.engine.clone();
+                        let stopped = stop.subscribe();
+                        connections.spawn(async move {
+                            if let Err(err) = handle_connection(engine, stream, stopped).await {
+                                error!("Unexpected error occurs when serving request: {:?}", err)
+                            }
+                        });
+                    }
+                    Err(err) => error!(
+                        "Unexpected error occurs when serving incoming request {:?}",
+                        err
+                    ),
+                },
+                _ = &mut shutdown => break,
+            }
+            while connections.try_join_next().is_some() {}
+        }
+
+        info!("Shutting down, draining open connections");
+        drop(listener);
+        stop.send_replace(true);
+        let drained = time::timeout(DRAIN_TIMEOUT, async {
+            while connections.join_next().await.is_some() {}
+        });
+        if drained.await.is_err() {
+            warn!("Connections still open after {:?}", DRAIN_TIMEOUT);
+            connections.abort_all();
+        }
+        let engine = self.engine.clone();
+        task::spawn_blocking(move || engine.flush())
+            .await
+            .map_err(join_error)?
+    }
+}
+
+/// serve requests on one connection until the peer closes it, it stays idle for too long or
+/// the server stops
+async fn handle_connection<E: KvsEngine>(
+    engine: E,
+    stream: TcpStream,
+    mut stopped: watch::Receiver<bool>,
+) -> Result<()> {
+    let mut connection = match time::timeout(IDLE_TIMEOUT, AsyncConnection::accept(stream)).await {
+        Ok(Ok(Some(connection))) => connection,
+        Ok(Ok(None)) | Err(_) => return Ok(()),
+        Ok(Err(err)) => return Err(err),
+    };
+    debug!("Accept {:?} connection", connection.protocol());
+
+    loop {
+        let request = tokio::select! {
+            read = time::timeout(IDLE_TIMEOUT, connection.read::<Request>()) => match read {
+                Ok(Ok(Some(request))) => request,
+                Ok(Ok(None)) => return Ok(()),
+                Ok(Err(err)) => return Err(err),
+                Err(_) => {
+                    debug!("Close idle connection");
+                    return Ok(());
+                }
+            },
+            // the request in flight is answered, but no other one is waited for
+            _ = stopped.wait_for(|stopped| *stopped) => return Ok(()),
+        };
+
+        let now = SystemTime::now();
+        log_request(&request);
+
+        let engine = engine.clone();
+        let response = task::spawn_blocking(move || process_request(&engine, request, None))
+            .await
+            .map_err(join_error)?;
+
+        debug!("Response: {:?}, {:?}", &response, now.elapsed());
+
+        connection.write(&response)?;
+        connection.flush().await?;
+    }
+}
+
+fn join_error(err: JoinError) -> KVStoreError {
+    KVStoreError::CommonStringError(format!("engine task failed: {}", err))
+}
@@ -0,0 +1,269 @@
+use crate::common::prefix_end;
+use crate::{KVStoreError, Request, Result};
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::{digest, pbkdf2};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, BufRead, Write};
+use std::num::NonZeroU32;
+use std::path::Path;
+
+/// name of the scheme of hashed passwords, the first field of a stored hash
+const HASH_SCHEME: &str = "pbkdf2-sha256";
+/// environment variable `read_password` takes a password from
+pub const PASSWORD_ENV: &str = "BLAZE_PASSWORD";
+/// PBKDF2 iterations for newly hashed passwords
+const HASH_ITERATIONS: u32 = 100_000;
+/// bytes of random salt for newly hashed passwords
+const SALT_LEN: usize = 16;
+/// a well-formed hash no password matches, checked for unknown users so that they take as
+/// long to reject as a wrong password
+const UNKNOWN_USER_HASH: &str = "pbkdf2-sha256$100000$00000000000000000000000000000000$\
+     0000000000000000000000000000000000000000000000000000000000000000";
+
+/** The users a `KvServer` accepts and the key prefixes each one may access, kept in a JSON
+file.
+
+A connection starts with the `anonymous` permissions, which grant nothing unless configured,
+and gets the permissions of a user once it authenticated as them with `Request::AUTH`.
+# Example
+```
+use blaze_turbo::{AccessControl, Permissions, Result};
+# fn try_main() -> Result<()> {
+
+let mut acl = AccessControl::default();
+let permissions = Permissions {
+    read: vec!["app/".to_owned()],
+    write: vec!["app/".to_owned()],
+    admin: false,
+};
+acl.set_user("alice", "secret", permissions.clone())?;
+assert_eq!(acl.authenticate("alice", "secret"), Some(&permissions));
+assert_eq!(acl.authenticate("alice", "guess"), None);
+# Ok(())
+# }
+```
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct AccessControl {
+    /// the users by name
+    #[serde(default)]
+    pub users: BTreeMap<String, User>,
+    /// the permissions of connections which have not authenticated
+    #[serde(default)]
+    pub anonymous: Permissions,
+}
+
+/// A user of `AccessControl`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct User {
+    /// the salted hash of the user's password, never the password itself
+    pub password: String,
+    /// what the user may access
+    #[serde(flatten)]
+    pub permissions: Permissions,
+}
+
+/// What a connection may access. Keys are granted by prefix, the empty prefix granting
+/// every key.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Permissions {
+    /// prefixes of the keys which may be read
+    #[serde(default)]
+    pub read: Vec<String>,
+    /// prefixes of the keys which may be set or removed
+    #[serde(default)]
+    pub write: Vec<String>,
+    /// whether compactions and backups may be requested
+    #[serde(default)]
+    pub admin: bool,
+}
+
+impl AccessControl {
+    /// Read the access control file at `path`.
+    pub fn load(path: impl AsRef<Path>) -> Result<AccessControl> {
+        Ok(serde_json::from_slice(&fs::read(path)?)?)
+    }
+
+    /// Replace the access control file at `path`. The new file is written to a temporary
+    /// file first, so a crash leaves either the old or the new one.
+    pub fn store(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        let temp_path = path.with_extension("tmp");
+        let mut file = File::create(&temp_path)?;
+        serde_json::to_writer_pretty(&mut file, self)?;
+        file.write_all(b"\n")?;
+        file.sync_all()?;
+        fs::rename(&temp_path, path)?;
+        Ok(())
+    }
+
+    /// Add a user, or replace the password and permissions of an existing one.
+    pub fn set_user(&mut self, name: &str, password: &str, permissions: Permissions) -> Result<()> {
+        let user = User {
+            password: hash_password(password)?,
+            permissions,
+        };
+        self.users.insert(name.to_owned(), user);
+        Ok(())
+    }
+
+    /// Return the permissions of the user if the password is theirs.
+    pub fn authenticate(&self, name: &str, password: &str) -> Option<&Permissions> {
+        match self.users.get(name) {
+            Some(user) if verify_password(&user.password, password) => Some(&user.permissions),
+            Some(_) => None,
+            None => {
+                verify_password(UNKNOWN_USER_HASH, password);
+                None
+            }
+        }
+    }
+}
+
+impl Permissions {
+    /// Check that a request only touches what these permissions grant. Return the reason
+    /// if it does not.
+    pub(crate) fn check(&self, request: &Request) -> std::result::Result<(), String> {
+        let key = match request {
+            Request::GET(key) => return self.check_read(key),
+            Request::SET(key, _) | Request::SETEX(key, _, _) | Request::RM(key) => key,
+            // a failed cas answers with the current value
+            Request::CAS(key, _, _) => {
+                self.check_read(key)?;
+                key
+            }
+            Request::BATCH(batch) => {
+                return batch.keys().try_for_each(|key| self.check_write(key));
+            }
+            Request::SCAN(start, end, _) => return self.check_scan(start, end.as_deref()),
+            Request::COMPACT | Request::BACKUP(_) if self.admin => return Ok(()),
+            Request::COMPACT | Request::BACKUP(_) => {
+                return Err("admin permission required".to_owned())
+            }
+            Request::AUTH(..) => return Ok(()),
+        };
+        self.check_write(key)
+    }
+
+    fn check_read(&self, key: &[u8]) -> std::result::Result<(), String> {
+        if granted(&self.read, key) {
+            Ok(())
+        } else {
+            Err(format!(
+                "no read access to {:?}",
+                String::from_utf8_lossy(key)
+            ))
+        }
+    }
+
+    fn check_write(&self, key: &[u8]) -> std::result::Result<(), String> {
+        if granted(&self.write, key) {
+            Ok(())
+        } else {
+            Err(format!(
+                "no write access to {:?}",
+                String::from_utf8_lossy(key)
+            ))
+        }
+    }
+
+    /// A scan is allowed if its whole range lies under one readable prefix.
+    fn check_scan(&self, start: &[u8], end: Option<&[u8]>) -> std::result::Result<(), String> {
+        let within = |prefix: &String| {
+            let prefix = prefix.as_bytes();
+            start.starts_with(prefix)
+                && match (prefix_end(prefix), end) {
+                    (None, _) => true,
+                    (Some(prefix_end), Some(end)) => end <= &prefix_end[..],
+                    (Some(_), None) => false,
+                }
+        };
+        if self.read.iter().any(within) {
+            Ok(())
+        } else {
+            Err("no read access to the whole scanned range".to_owned())
+        }
+    }
+}
+
+fn granted(prefixes: &[String], key: &[u8]) -> bool {
+    prefixes
+        .iter()
+        .any(|prefix| key.starts_with(prefix.as_bytes()))
+}
+
+/// Read a password for the command line tools, so it never has to be an argument visible to
+/// other users: from the first line of `file` if given, else from the `BLAZE_PASSWORD`
+/// environment variable if set, else from a line of standard input after a prompt.
+pub fn read_password(file: Option<&Path>) -> Result<String> {
+    let line = match file {
+        Some(file) => fs::read_to_string(file)?,
+        None => match std::env::var(PASSWORD_ENV) {
+            Ok(password) => return Ok(password),
+            Err(_) => {
+                eprint!("Password: ");
+                io::stderr().flush()?;
+                let mut line = String::new();
+                if io::stdin().lock().read_line(&mut line)? == 0 {
+                    return Err(KVStoreError::CommonStringError(
+                        "no password on standard input".to_owned(),
+                    ));
+                }
+                line
+            }
+        },
+    };
+    let line = line.lines().next().unwrap_or_default();
+    Ok(line.to_owned())
+}
+
+/// Hash a password with PBKDF2-HMAC-SHA256 and a random salt, as
+/// `pbkdf2-sha256$<ITERATIONS>$<SALT>$<HASH>` with the salt and hash in hex.
+pub fn hash_password(password: &str) -> Result<String> {
+    let mut salt = [0; SALT_LEN];
+    SystemRandom::new()
+        .fill(&mut salt)
+        .map_err(|_| KVStoreError::CommonStringError("no random salt available".to_owned()))?;
+    let iterations = NonZeroU32::new(HASH_ITERATIONS).unwrap();
+    let mut hash = [0; digest::SHA256_OUTPUT_LEN];
+    pbkdf2::derive(
+        pbkdf2::PBKDF2_HMAC_SHA256,
+        iterations,
+        &salt,
+        password.as_bytes(),
+        &mut hash,
+    );
+    Ok(format!(
+        "{}${}${}${}",
+        HASH_SCHEME,
+        iterations,
+        hex::encode(salt),
+        hex::encode(hash)
+    ))
+}
+
+/// Check a password against a hash produced by `hash_password`, in constant time.
+fn verify_password(hash: &str, password: &str) -> bool {
+    let fields: Vec<&str> = hash.split('$').collect();
+    let [scheme, iterations, salt, hash] = fields[..] else {
+        return false;
+    };
+    let (Some(iterations), Ok(salt), Ok(hash)) = (
+        iterations.parse().ok().and_then(NonZeroU32::new),
+        hex::decode(salt),
+        hex::decode(hash),
+    ) else {
+        return false;
+    };
+    scheme == HASH_SCHEME
+        && pbkdf2::verify(
+            pbkdf2::PBKDF2_HMAC_SHA256,
+            iterations,
+            &salt,
+            password.as_bytes(),
+            &hash,
+        )
+        .is_ok()
+}
@@ -0,0 +1,247 @@
+use blaze_turbo::{read_password, Permissions, Result};
+use blaze_turbo::{AccessControl, EngineType, KVStoreError, KvStore, KvsEngine, Manifest};
+use blaze_turbo::{KvEntries, SledKvsEngine, WriteBatch};
+use clap::{arg, command, ArgMatches, SubCommand};
+use log::{info, LevelFilter};
+use std::fs;
+use std::path::Path;
+use std::{env, process};
+
+/// number of key/value pairs read and written at once while migrating
+const MIGRATE_CHUNK_SIZE: usize = 1024;
+
+fn main() {
+    env_logger::builder().filter_level(LevelFilter::Info).init();
+    let matches = command!()
+        .name("blaze-admin")
+        .subcommand(
+            SubCommand::with_name("migrate")
+                .about("Copy every live key of the store into a new store of another engine, then mark the old store as migrated. Keys set with a ttl keep the time they have left. The server must be stopped.")
+                .arg(arg!(--from <ENGINENAME>).value_parser(["kvs", "sled"]))
+                .arg(arg!(--to <ENGINENAME>).value_parser(["kvs", "sled"])),
+        )
+        .subcommand(
+            SubCommand::with_name("restore")
+                .about("Create the store of the working directory from a snapshot written by blaze-client backup. The server must be stopped.")
+                .arg(arg!(<SNAPSHOT>)),
+        )
+        .subcommand(
+            SubCommand::with_name("set-user")
+                .about("Add a user to the access control file given to blaze-server --acl, or replace the password and permissions of an existing one. The file is created if it does not exist.")
+                .arg(arg!(<NAME>))
+                .arg(arg!(--acl <FILE>))
+                .arg(arg!(--"password-file" <FILE> "Read the password from the first line of this file instead of $BLAZE_PASSWORD or a prompt").required(false))
+                .arg(arg!(--read <PREFIX> "Allow reading the keys starting with PREFIX, all keys for \"\"").required(false).multiple_occurrences(true))
+                .arg(arg!(--write <PREFIX> "Allow setting and removing the keys starting with PREFIX").required(false).multiple_occurrences(true))
+                .arg(arg!(--admin "Allow requesting compactions and backups")),
+        )
+        .subcommand(
+            SubCommand::with_name("remove-user")
+                .about("Remove a user from an access control file.")
+                .arg(arg!(<NAME>))
+                .arg(arg!(--acl <FILE>)),
+        )
+        .get_matches();
+    if let Err(err) = run(matches) {
+        eprintln!("{:?}", err);
+        process::exit(-1);
+    }
+}
+
+fn run(matches: ArgMatches) -> Result<()> {
+    match matches.subcommand() {
+        Some(("migrate", sub_matches)) => {
+            let from: EngineType = sub_matches.get_one::<String>("from").unwrap().parse()?;
+            let to: EngineType = sub_matches.get_one::<String>("to").unwrap().parse()?;
+            migrate(&env::current_dir()?, from, to)?;
+        }
+        Some(("restore", sub_matches)) => {
+            let snapshot = sub_matches.get_one::<String>("SNAPSHOT").unwrap();
+            restore(&env::current_dir()?, Path::new(snapshot))?;
+        }
+        Some(("set-user", sub_matches)) => {
+            let path = sub_matches.get_one::<String>("acl").unwrap();
+            let prefixes = |name| {
+                sub_matches
+                    .get_many::<String>(name)
+                    .map_or(Vec::new(), |prefixes| prefixes.cloned().collect())
+            };
+            let permissions = Permissions {
+                read: prefixes("read"),
+                write: prefixes("write"),
+                admin: sub_matches.contains_id("admin"),
+            };
+            let mut acl = if Path::new(path).exists() {
+                AccessControl::load(path)?
+            } else {
+                AccessControl::default()
+            };
+            let file = sub_matches
+                .get_one::<String>("password-file")
+                .map(Path::new);
+            acl.set_user(
+                sub_matches.get_one::<String>("NAME").unwrap(),
+                &read_password(file)?,
+                permissions,
+            )?;
+            acl.store(path)?;
+        }
+        Some(("remove-user", sub_matches)) => {
+            let path = sub_matches.get_one::<String>("acl").unwrap();
+            let name = sub_matches.get_one::<String>("NAME").unwrap();
+            let mut acl = AccessControl::load(path)?;
+            if acl.users.remove(name).is_none() {
+                return Err(KVStoreError::CommonStringError(format!(
+                    "no user {:?} in {:?}",
+                    name, path
+                )));
+            }
+            acl.store(path)?;
+        }
+        _ => process::exit(-1),
+    }
+    Ok(())
+}
+
+/// Migrate the store of engine `from` in `dir` to a new store of engine `to` next to it.
+fn migrate(dir: &Path, from: EngineType, to: EngineType) -> Result<()> {
+    if from == to {
+        return Err(KVStoreError::CommonStringError(format!(
+            "the store already uses {}",
+            to
+        )));
+    }
+    let from_path = dir.join(from.to_string());
+    let to_path = dir.join(to.to_string());
+    if !from_path.exists() {
+        return Err(KVStoreError::CommonStringError(format!(
+            "no {} store in {:?}",
+            from, dir
+        )));
+    }
+    if to_path.exists() {
+        return Err(KVStoreError::CommonStringError(format!(
+            "{:?} already exists, remove it before migrating",
+            to_path
+        )));
+    }
+
+    let count = match (from, to) {
+        (EngineType::KvStore, EngineType::SledKvsEngine) => {
+            copy_keys(&KvStore::open(&from_path)?, &SledKvsEngine::open(&to_path)?)?
+        }
+        (EngineType::SledKvsEngine, EngineType::KvStore) => {
+            copy_keys(&SledKvsEngine::open(&from_path)?, &KvStore::open(&to_path)?)?
+        }
+        _ => unreachable!("engines are different"),
+    };
+
+    // only now the server starts with the new store
+    let mut manifest = Manifest::load(&from_path)?.ok_or(KVStoreError::UnknownEngineType)?;
+    manifest.migrated_to = Some(to);
+    manifest.store(&from_path)?;
+    info!("Migrated {} keys from {} to {}", count, from, to);
+    Ok(())
+}
+
+/// Copy the snapshot into a new store in `dir`, of the engine which wrote the snapshot.
+fn restore(dir: &Path, snapshot: &Path) -> Result<()> {
+    let manifest = Manifest::load(snapshot)?.ok_or_else(|| {
+        KVStoreError::CommonStringError(format!("{:?} is not a snapshot", snapshot))
+    })?;
+    for engine in [EngineType::KvStore, EngineType::SledKvsEngine] {
+        let path = dir.join(engine.to_string());
+        if path.exists() {
+            return Err(KVStoreError::CommonStringError(format!(
+                "{:?} already exists, remove it before restoring",
+                path
+            )));
+        }
+    }
+
+    let path = dir.join(manifest.engine.to_string());
+    copy_dir(snapshot, &path)?;
+    // the copy must open as a store
+    let mut count = 0;
+    let mut count_chunk = |pairs: KvEntries| {
+        count += pairs.len();
+        Ok(())
+    };
+    match manifest.engine {
+        EngineType::KvStore => for_each_chunk(&KvStore::open(&path)?, &mut count_chunk)?,
+        EngineType::SledKvsEngine => {
+            for_each_chunk(&SledKvsEngine::open(&path)?, &mut count_chunk)?
+        }
+    }
+    info!("Restored {} keys into {:?}", count, path);
+    Ok(())
+}
+
+/// Copy the files of the directory `source` into `dest`, except the lock of a store.
+fn copy_dir(source: &Path, dest: &Path) -> Result<()> {
+    fs::create_dir_all(dest)?;
+    for entry in fs::read_dir(source)? {
+        let entry = entry?;
+        let dest = dest.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &dest)?;
+        } else if entry.file_name() != "LOCK" {
+            fs::copy(entry.path(), dest)?;
+        }
+    }
+    Ok(())
+}
+
+/// Copy every key from `source` to `dest` in chunks, with the ttl it has left, then check
+/// that `dest` holds as many keys. Keys with a ttl may expire meanwhile, so `dest` may hold
+/// fewer of those. Return the number of keys copied.
+fn copy_keys(source: &impl KvsEngine, dest: &impl KvsEngine) -> Result<usize> {
+    let (mut count, mut count_with_ttl) = (0, 0);
+    for_each_chunk(source, |pairs| {
+        count += pairs.len();
+        let mut batch = WriteBatch::new();
+        for (key, value, ttl) in pairs {
+            match ttl {
+                Some(ttl) => {
+                    count_with_ttl += 1;
+                    batch.set_with_ttl(key, value, ttl);
+                }
+                None => batch.set(key, value),
+            }
+        }
+        dest.write_batch(batch)
+    })?;
+
+    let (mut copied, mut copied_with_ttl) = (0, 0);
+    for_each_chunk(dest, |pairs| {
+        copied += pairs.len();
+        copied_with_ttl += pairs.iter().filter(|(_, _, ttl)| ttl.is_some()).count();
+        Ok(())
+    })?;
+    if copied - copied_with_ttl != count - count_with_ttl || copied_with_ttl > count_with_ttl {
+        return Err(KVStoreError::CommonStringError(format!(
+            "copied {} keys but the new store holds {}",
+            count, copied
+        )));
+    }
+    Ok(count)
+}
+
+/// Scan every key/value pair of `engine` in key order, with the ttl left of each, handing them
+/// to `f` in chunks.
+fn for_each_chunk(
+    engine: &impl KvsEngine,
+    mut f: impl FnMut(KvEntries) -> Result<()>,
+) -> Result<()> {
+    let mut start = Vec::new();
+    loop {
+        let pairs = engine.scan_with_ttl(&start, None, Some(MIGRATE_CHUNK_SIZE))?;
+        let Some((last, _, _)) = pairs.last() else {
+            return Ok(());
+        };
+        // the smallest key after the last one of the chunk
+        start = last.clone();
+        start.push(0);
+        f(pairs)?;
+    }
+}
@@ -1,5 +1,9 @@
-use blaze_turbo::{Client, Request, Result};
-use clap::{arg, command, ArgMatches, SubCommand};
+use blaze_turbo::{
+    read_password, Client, ClientTlsConfig, KVStoreError, Protocol, Request, Result,
+};
+use clap::{arg, command, Arg, ArgMatches, SubCommand};
+use std::io::{self, Write};
+use std::path::Path;
 use std::string::String;
 use std::{env, process};
 
@@ -11,19 +15,56 @@ fn main() {
                 .about("Set the value of a string key to a string. Return an error if the value is not written successfully.")
                 .arg(arg!(<KEY>))
                 .arg(arg!(<VALUE>))
-                .arg(arg!(--addr <IPPORT>).required(false).default_value("127.0.0.1:4000")),
+                .arg(
+                    arg!(--ttl <SECONDS> "Expire the key after the given number of seconds")
+                        .required(false)
+                        .value_parser(clap::value_parser!(u64)),
+                )
+                .args(connection_args()),
         )
         .subcommand(
             SubCommand::with_name("get")
                 .about("Get the string value of a string key. If the key does not exist, return None. Return an error if the value is not read successfully.")
                 .arg(arg!(<KEY>))
-                .arg(arg!(--addr <IPPORT>).required(false).default_value("127.0.0.1:4000")),
+                .args(connection_args()),
         )
         .subcommand(
             SubCommand::with_name("rm")
                 .about("Remove a given key. Return an error if the key does not exist or is not removed successfully.c")
                 .arg(arg!(<KEY>))
-                .arg(arg!(--addr <IPPORT>).required(false).default_value("127.0.0.1:4000")),
+                .args(connection_args()),
+        )
+        .subcommand(
+            SubCommand::with_name("cas")
+                .about("Set the value of a key to NEW, or remove it if NEW is omitted, only if its current value is EXPECTED. Without --expected the key must not exist.")
+                .arg(arg!(<KEY>))
+                .arg(arg!([NEW]))
+                .arg(arg!(--expected <EXPECTED>).required(false))
+                .args(connection_args()),
+        )
+        .subcommand(
+            SubCommand::with_name("scan")
+                .about("List key/value pairs with START <= key < END in key order, one tab separated pair per line.")
+                .arg(arg!([START]).conflicts_with("prefix"))
+                .arg(arg!([END]).conflicts_with("prefix"))
+                .arg(arg!(--prefix <PREFIX>).required(false))
+                .arg(
+                    arg!(--limit <COUNT>)
+                        .required(false)
+                        .value_parser(clap::value_parser!(usize)),
+                )
+                .args(connection_args()),
+        )
+        .subcommand(
+            SubCommand::with_name("compact")
+                .about("Reclaim the space of overwritten and removed keys now. Return once the compaction is finished.")
+                .args(connection_args()),
+        )
+        .subcommand(
+            SubCommand::with_name("backup")
+                .about("Write a consistent snapshot of the store to DEST, a new or empty directory inside the --backup-dir of the server. Restore it with blaze-admin restore.")
+                .arg(arg!(<DEST>))
+                .args(connection_args()),
         )
         .get_matches();
     if let Err(err) = send_request(matches) {
@@ -32,29 +73,136 @@ fn main() {
     }
 }
 
+/// the options every subcommand takes to reach the server
+fn connection_args() -> [Arg<'static>; 6] {
+    [
+        arg!(--addr <IPPORT> "host:port, or unix:<PATH> for a Unix domain socket")
+            .required(false)
+            .default_value("127.0.0.1:4000"),
+        arg!(--"tls-ca" <FILE> "Connect over TLS, trusting the CA certificates in this PEM file")
+            .required(false),
+        arg!(--"tls-cert" <FILE> "Client certificate chain in PEM, for servers which require mutual TLS")
+            .required(false)
+            .requires_all(&["tls-ca", "tls-key"]),
+        arg!(--"tls-key" <FILE> "Private key of the client certificate in PEM")
+            .required(false)
+            .requires("tls-cert"),
+        arg!(--user <NAME> "Authenticate as this user").required(false),
+        arg!(--"password-file" <FILE> "Read the password of the user from the first line of this file instead of $BLAZE_PASSWORD or a prompt").required(false).requires("user"),
+    ]
+}
+
+fn connect(matches: &ArgMatches) -> Result<Client> {
+    let addr = matches.get_one::<String>("addr").unwrap();
+    let mut client = match (
+        matches.get_one::<String>("tls-ca"),
+        matches.get_one::<String>("tls-cert"),
+        matches.get_one::<String>("tls-key"),
+    ) {
+        (Some(ca), Some(cert), Some(key)) => Client::connect_tls(
+            addr,
+            Protocol::Binary,
+            &ClientTlsConfig::with_client_cert(ca, cert, key)?,
+        )?,
+        (Some(ca), _, _) => {
+            Client::connect_tls(addr, Protocol::Binary, &ClientTlsConfig::new(ca)?)?
+        }
+        _ => Client::new(addr)?,
+    };
+    if let Some(user) = matches.get_one::<String>("user") {
+        let file = matches.get_one::<String>("password-file").map(Path::new);
+        client.authenticate(user, &read_password(file)?)?;
+    }
+    Ok(client)
+}
+
 fn send_request(matches: ArgMatches) -> Result<()> {
     match matches.subcommand() {
         Some(("set", sub_matches)) => {
-            let addr = sub_matches.get_one::<String>("addr").unwrap();
             let key = sub_matches.get_one::<String>("KEY").unwrap();
             let value = sub_matches.get_one::<String>("VALUE").unwrap();
-            let mut client = Client::new(addr)?;
-            client.request(&Request::SET(key.to_owned(), value.to_owned()))?;
+            let mut client = connect(sub_matches)?;
+            let (key, value) = (key.as_bytes().to_vec(), value.as_bytes().to_vec());
+            let request = match sub_matches.get_one::<u64>("ttl") {
+                Some(ttl) => Request::SETEX(key, value, ttl.saturating_mul(1000)),
+                None => Request::SET(key, value),
+            };
+            client.request(&request)?;
         }
         Some(("get", sub_matches)) => {
-            let addr = sub_matches.get_one::<String>("addr").unwrap();
             let key = sub_matches.get_one::<String>("KEY").unwrap();
-            let mut client = Client::new(addr)?;
-            match client.request(&Request::GET(key.to_owned()))? {
+            let mut client = connect(sub_matches)?;
+            match client.request(&Request::GET(key.as_bytes().to_vec()))? {
                 None => println!("Key not found"),
-                Some(value) => println!("{}", value),
+                Some(value) => {
+                    let mut stdout = io::stdout();
+                    stdout.write_all(&value)?;
+                    stdout.write_all(b"\n")?;
+                }
             };
         }
         Some(("rm", sub_matches)) => {
-            let addr = sub_matches.get_one::<String>("addr").unwrap();
             let key = sub_matches.get_one::<String>("KEY").unwrap();
-            let mut client = Client::new(addr)?;
-            client.request(&Request::RM(key.to_owned()))?;
+            let mut client = connect(sub_matches)?;
+            client.request(&Request::RM(key.as_bytes().to_vec()))?;
+        }
+        Some(("cas", sub_matches)) => {
+            let key = sub_matches.get_one::<String>("KEY").unwrap();
+            let expected = sub_matches.get_one::<String>("expected");
+            let new = sub_matches.get_one::<String>("NEW");
+            let mut client = connect(sub_matches)?;
+            let request = Request::CAS(
+                key.as_bytes().to_vec(),
+                expected.map(|value| value.as_bytes().to_vec()),
+                new.map(|value| value.as_bytes().to_vec()),
+            );
+            match client.request(&request) {
+                Err(KVStoreError::CompareAndSwapFailed(current)) => {
+                    match current {
+                        None => eprintln!("Compare and swap failed, key not found"),
+                        Some(value) => eprintln!(
+                            "Compare and swap failed, current value: {}",
+                            String::from_utf8_lossy(&value)
+                        ),
+                    }
+                    process::exit(-1);
+                }
+                result => {
+                    result?;
+                }
+            }
+        }
+        Some(("scan", sub_matches)) => {
+            let mut client = connect(sub_matches)?;
+            let limit = sub_matches.get_one::<usize>("limit").copied();
+            let pairs = match sub_matches.get_one::<String>("prefix") {
+                Some(prefix) => client.scan_prefix(prefix.as_bytes(), limit)?,
+                None => client.scan(
+                    sub_matches
+                        .get_one::<String>("START")
+                        .map_or(Vec::new(), |start| start.as_bytes().to_vec()),
+                    sub_matches
+                        .get_one::<String>("END")
+                        .map(|end| end.as_bytes().to_vec()),
+                    limit,
+                )?,
+            };
+            let mut stdout = io::stdout();
+            for (key, value) in pairs {
+                stdout.write_all(&key)?;
+                stdout.write_all(b"\t")?;
+                stdout.write_all(&value)?;
+                stdout.write_all(b"\n")?;
+            }
+        }
+        Some(("compact", sub_matches)) => {
+            let mut client = connect(sub_matches)?;
+            client.request(&Request::COMPACT)?;
+        }
+        Some(("backup", sub_matches)) => {
+            let dest = sub_matches.get_one::<String>("DEST").unwrap();
+            let mut client = connect(sub_matches)?;
+            client.request(&Request::BACKUP(dest.clone()))?;
         }
         _ => process::exit(-1),
     }
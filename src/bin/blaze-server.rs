@@ -1,9 +1,11 @@
-use blaze_turbo::{EngineType, KVStoreError, KvServer, KvStore, KvsEngine, Result, SledKvsEngine};
-use blaze_turbo::{SharedQueueThreadPool, ThreadPool};
+use blaze_turbo::{
+    AccessControl, EngineType, KVStoreError, KvServer, KvStore, KvsEngine, Result, SledKvsEngine,
+};
+use blaze_turbo::{KvStoreOptions, Manifest, SledKvsEngineOptions, SyncPolicy};
+use blaze_turbo::{ServerTlsConfig, SharedQueueThreadPool, ThreadPool};
 use clap::{arg, command, ArgMatches};
-use log::{info, LevelFilter};
-use std::sync::atomic::AtomicBool;
-use std::sync::Arc;
+use log::{info, warn, LevelFilter};
+use std::path::{Path, PathBuf};
 use std::{env, process};
 
 fn main() -> Result<()> {
@@ -11,7 +13,7 @@ fn main() -> Result<()> {
     let matches = command!()
         .name("blaze-server")
         .arg(
-            arg!(--addr <IPPORT>)
+            arg!(--addr <IPPORT> "host:port, or unix:<PATH> for a Unix domain socket")
                 .required(false)
                 .default_value("127.0.0.1:4000"),
         )
@@ -20,6 +22,41 @@ fn main() -> Result<()> {
                 .required(false)
                 .value_parser(["kvs", "sled"]),
         )
+        .arg(
+            arg!(--sync <POLICY> "When to sync writes to disk: never, every-write, every-n:<WRITES> or interval:<MILLISECONDS>")
+                .required(false)
+                .default_value("never")
+                .value_parser(|policy: &str| policy.parse::<SyncPolicy>().map_err(|err| err.to_string())),
+        )
+        .arg(
+            arg!(--"tls-cert" <FILE> "Serve over TLS with the certificate chain in this PEM file")
+                .required(false)
+                .requires("tls-key"),
+        )
+        .arg(
+            arg!(--"tls-key" <FILE> "Private key of the TLS certificate in PEM")
+                .required(false)
+                .requires("tls-cert"),
+        )
+        .arg(
+            arg!(--acl <FILE> "Require clients to authenticate as the users in this access control file, and restrict them to the keys it grants")
+                .required(false),
+        )
+        .arg(
+            arg!(--"backup-dir" <DIR> "Write the snapshots requested with blaze-client backup into this directory. Backups are refused without it")
+                .required(false),
+        )
+        .arg(
+            arg!(--"max-connections" <COUNT> "Serve up to COUNT connections at once, each with a thread of its own, and close the ones beyond")
+                .required(false)
+                .default_value("64")
+                .value_parser(clap::value_parser!(u32).range(1..)),
+        )
+        .arg(
+            arg!(--"tls-client-ca" <FILE> "Require clients to present a certificate issued by a CA in this PEM file")
+                .required(false)
+                .requires("tls-cert"),
+        )
         .get_matches();
     if let Err(err) = init(matches) {
         eprintln!("{:?}", err);
@@ -31,54 +68,140 @@ fn main() -> Result<()> {
 fn init(matches: ArgMatches) -> Result<()> {
     let addr = matches.get_one::<String>("addr").unwrap();
     let engine_type = judge_engine(matches.get_one::<String>("engine").cloned())?;
+    let sync_policy = *matches.get_one::<SyncPolicy>("sync").unwrap();
+    let tls = server_tls(&matches)?;
+    let acl = matches
+        .get_one::<String>("acl")
+        .map(AccessControl::load)
+        .transpose()?;
+    let backup_dir = matches.get_one::<String>("backup-dir").map(PathBuf::from);
+    let max_connections = *matches.get_one::<u32>("max-connections").unwrap() as usize;
 
     info!("Version: [{}]", env!("CARGO_PKG_VERSION"));
     info!("Addr: [{}]", addr);
     info!("Engine: [{}]", engine_type);
+    info!("Sync: [{}]", sync_policy);
+    info!("Max connections: [{}]", max_connections);
+    info!(
+        "TLS: [{}]",
+        match (&tls, matches.contains_id("tls-client-ca")) {
+            (None, _) => "off",
+            (Some(_), false) => "on",
+            (Some(_), true) => "mutual",
+        }
+    );
+    info!(
+        "Access control: [{}]",
+        match &acl {
+            None => "off".to_owned(),
+            Some(acl) => format!("{} users", acl.users.len()),
+        }
+    );
+    info!(
+        "Backups: [{}]",
+        match &backup_dir {
+            None => "off".to_owned(),
+            Some(dir) => format!("{:?}", dir),
+        }
+    );
+
+    if acl.is_some() && tls.is_none() {
+        warn!("Access control without --tls-cert: passwords are sent to the server in cleartext");
+    }
 
     match engine_type {
         EngineType::KvStore => run_server(
-            KvStore::open(env::current_dir()?.join(EngineType::KvStore.to_string()))?,
+            KvStore::open_with(
+                env::current_dir()?.join(EngineType::KvStore.to_string()),
+                KvStoreOptions::new().sync_policy(sync_policy),
+            )?,
             addr,
+            tls,
+            acl,
+            backup_dir.clone(),
+            max_connections,
         ),
         EngineType::SledKvsEngine => run_server(
-            SledKvsEngine::open(env::current_dir()?.join(EngineType::SledKvsEngine.to_string()))?,
+            SledKvsEngine::open_with(
+                env::current_dir()?.join(EngineType::SledKvsEngine.to_string()),
+                SledKvsEngineOptions::new().sync_policy(sync_policy),
+            )?,
             addr,
+            tls,
+            acl,
+            backup_dir,
+            max_connections,
         ),
     }
 }
 
+fn server_tls(matches: &ArgMatches) -> Result<Option<ServerTlsConfig>> {
+    let (cert, key) = match (
+        matches.get_one::<String>("tls-cert"),
+        matches.get_one::<String>("tls-key"),
+    ) {
+        (Some(cert), Some(key)) => (cert, key),
+        _ => return Ok(None),
+    };
+    Ok(Some(match matches.get_one::<String>("tls-client-ca") {
+        Some(client_ca) => ServerTlsConfig::with_client_auth(cert, key, client_ca)?,
+        None => ServerTlsConfig::new(cert, key)?,
+    }))
+}
+
 fn judge_engine(engine: Option<String>) -> Result<EngineType> {
-    let dir = env::current_dir()?;
-    match engine {
-        None => {
-            if dir.join(EngineType::SledKvsEngine.to_string()).exists() {
-                return Ok(EngineType::SledKvsEngine);
-            }
-            Ok(EngineType::KvStore)
+    let existing = existing_engine(&env::current_dir()?)?;
+    let engine = engine.map(|engine| engine.parse()).transpose()?;
+    match (engine, existing) {
+        (Some(engine), Some(existing)) if engine != existing => {
+            Err(KVStoreError::ChangeEngineError)
+        }
+        (engine, existing) => Ok(engine.or(existing).unwrap_or(EngineType::KvStore)),
+    }
+}
+
+/// Return the engine of the store in `dir`, as recorded in its manifest, or as named by its
+/// directory if it was created before stores had manifests. Stores which were migrated to
+/// another engine are skipped.
+fn existing_engine(dir: &Path) -> Result<Option<EngineType>> {
+    for engine in [EngineType::SledKvsEngine, EngineType::KvStore] {
+        let path = dir.join(engine.to_string());
+        match Manifest::load(&path)? {
+            Some(manifest) if manifest.migrated_to.is_some() => continue,
+            Some(manifest) => return Ok(Some(manifest.engine)),
+            None => {}
         }
-        Some(v) => {
-            if v == EngineType::KvStore.to_string() {
-                if dir.join(EngineType::SledKvsEngine.to_string()).exists() {
-                    return Err(KVStoreError::ChangeEngineError);
-                }
-                Ok(EngineType::KvStore)
-            } else {
-                if dir.join(EngineType::KvStore.to_string()).exists() {
-                    return Err(KVStoreError::ChangeEngineError);
-                }
-                Ok(EngineType::SledKvsEngine)
-            }
+        if path.exists() {
+            return Ok(Some(engine));
         }
     }
+    Ok(None)
 }
 
-fn run_server<E: KvsEngine>(engine: E, addr: &String) -> Result<()> {
-    let mut server = KvServer::new(
-        engine,
-        SharedQueueThreadPool::new(num_cpus::get())?,
-        Arc::new(AtomicBool::new(false)),
-    );
+fn run_server<E: KvsEngine>(
+    engine: E,
+    addr: &str,
+    tls: Option<ServerTlsConfig>,
+    acl: Option<AccessControl>,
+    backup_dir: Option<PathBuf>,
+    max_connections: usize,
+) -> Result<()> {
+    // one thread per connection, so an idle connection never keeps another one waiting
+    let pool = SharedQueueThreadPool::new(max_connections)?;
+    let mut server = KvServer::new(engine, pool).max_connections(max_connections);
+    if let Some(tls) = tls {
+        server = server.tls(tls);
+    }
+    if let Some(acl) = acl {
+        server = server.access_control(acl);
+    }
+    if let Some(backup_dir) = backup_dir {
+        server = server.backup_dir(backup_dir);
+    }
+    let shutdown = server.shutdown_handle();
+    ctrlc::set_handler(move || shutdown.shutdown())
+        .map_err(|err| KVStoreError::CommonStringError(err.to_string()))?;
     server.serve(addr)?;
+    info!("Server stopped");
     Ok(())
 }
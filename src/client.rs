@@ -1,33 +1,196 @@
-use crate::{KVStoreError, Request, Response, Result};
-use serde::Deserialize;
-use serde_json::de::IoRead;
-use serde_json::Deserializer;
-use std::io::{BufReader, BufWriter, Write};
-use std::net::TcpStream;
-
-/// a tcp client which can connect to blaze-server
+use crate::common::prefix_end;
+use crate::proto::Connection;
+use crate::transport::Stream;
+use crate::{ClientTlsConfig, KVStoreError, KvPairs, Protocol, Request, Response, Result};
+use std::io;
+
+/// the most bytes of requests a pipeline sends before reading their answers, small enough to
+/// fit in the socket buffers while the server blocks writing answers
+const PIPELINE_WINDOW: usize = 64 * 1024;
+
+/// a client which can connect to blaze-server, over TCP or a Unix domain socket, and reuse
+/// the connection for many requests
 pub struct Client {
-    reader: Deserializer<IoRead<BufReader<TcpStream>>>,
-    writer: BufWriter<TcpStream>,
+    connection: Connection<Stream>,
 }
 
 impl Client {
-    /// init a client
+    /// init a client which speaks the binary protocol.
+    /// addr is either a TCP `host:port` or `unix:<PATH>` for a Unix domain socket.
     pub fn new(addr: &str) -> Result<Client> {
-        let stream = TcpStream::connect(addr)?;
+        Client::connect(addr, Protocol::Binary)
+    }
+
+    /// init a client which speaks the given protocol
+    pub fn connect(addr: &str, protocol: Protocol) -> Result<Client> {
+        let stream = Stream::connect(addr)?;
         Ok(Client {
-            reader: Deserializer::from_reader(BufReader::new(stream.try_clone()?)),
-            writer: BufWriter::new(stream),
+            connection: Connection::connect(stream, protocol)?,
         })
     }
 
+    /// init a client which speaks the given protocol over TLS
+    pub fn connect_tls(addr: &str, protocol: Protocol, tls: &ClientTlsConfig) -> Result<Client> {
+        let stream = Stream::connect_tls(addr, tls)?;
+        Ok(Client {
+            connection: Connection::connect(stream, protocol)?,
+        })
+    }
+
+    /// authenticate the connection, so later requests get the permissions of the user
+    pub fn authenticate(&mut self, user: &str, password: &str) -> Result<()> {
+        self.request(&Request::AUTH(user.to_owned(), password.to_owned()))?;
+        Ok(())
+    }
+
     /// perform a request
-    pub fn request(&mut self, request: &Request) -> Result<Option<String>> {
-        serde_json::to_writer(&mut self.writer, request)?;
-        self.writer.flush()?;
-        match Response::deserialize(&mut self.reader)? {
-            Response::Ok(value) => Ok(value),
-            Response::Err(err) => Err(KVStoreError::CommonStringError(err)),
+    pub fn request(&mut self, request: &Request) -> Result<Option<Vec<u8>>> {
+        self.connection.write(request)?;
+        self.connection.flush()?;
+        self.read_result()?
+    }
+
+    /// return up to `limit` key/value pairs with `start <= key < end`, in key order
+    pub fn scan(
+        &mut self,
+        start: impl Into<Vec<u8>>,
+        end: Option<Vec<u8>>,
+        limit: Option<usize>,
+    ) -> Result<KvPairs> {
+        self.connection
+            .write(&Request::SCAN(start.into(), end, limit))?;
+        self.connection.flush()?;
+        scan_result(self.read_message()?)
+    }
+
+    /// return up to `limit` key/value pairs whose key starts with `prefix`, in key order
+    pub fn scan_prefix(
+        &mut self,
+        prefix: impl Into<Vec<u8>>,
+        limit: Option<usize>,
+    ) -> Result<KvPairs> {
+        let prefix = prefix.into();
+        let end = prefix_end(&prefix);
+        self.scan(prefix, end, limit)
+    }
+
+    /// start a pipeline which sends several requests before reading any response
+    pub fn pipeline(&mut self) -> Pipeline<'_> {
+        Pipeline {
+            client: self,
+            requests: Vec::new(),
+        }
+    }
+
+    /// read the result of a single value request.
+    /// The outer error means the connection failed, the inner one is the request's own error.
+    fn read_result(&mut self) -> Result<Result<Option<Vec<u8>>>> {
+        Ok(request_result(self.read_message()?))
+    }
+
+    fn read_message(&mut self) -> Result<Response> {
+        self.connection
+            .read::<Response>()?
+            .ok_or_else(closed_by_server)
+    }
+}
+
+/// turn the response to a single value request into its result
+pub(crate) fn request_result(response: Response) -> Result<Option<Vec<u8>>> {
+    match response {
+        Response::Ok(value) => Ok(value),
+        Response::CasFailed(current) => Err(KVStoreError::CompareAndSwapFailed(current)),
+        Response::Err(err) => Err(KVStoreError::CommonStringError(err)),
+        Response::PermissionDenied(reason) => Err(KVStoreError::PermissionDenied(reason)),
+        Response::Entries(_) => Err(KVStoreError::UnexpectedResponse),
+    }
+}
+
+/// turn the response to a scan request into its result
+pub(crate) fn scan_result(response: Response) -> Result<KvPairs> {
+    match response {
+        Response::Entries(pairs) => Ok(pairs),
+        Response::Err(err) => Err(KVStoreError::CommonStringError(err)),
+        Response::PermissionDenied(reason) => Err(KVStoreError::PermissionDenied(reason)),
+        _ => Err(KVStoreError::UnexpectedResponse),
+    }
+}
+
+/// the error of a request whose connection the server closed before answering
+pub(crate) fn closed_by_server() -> KVStoreError {
+    KVStoreError::Io(io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        "connection closed by server",
+    ))
+}
+
+/** A batch of requests which are written back to back and answered in order.
+# Example
+```no_run
+use blaze_turbo::{Client, Result};
+# fn try_main() -> Result<()> {
+let mut client = Client::new("127.0.0.1:4000")?;
+let results = client
+    .pipeline()
+    .set("1", "1")
+    .get("1")
+    .execute()?;
+assert_eq!(results.len(), 2);
+# Ok(())
+# }
+```
+ */
+pub struct Pipeline<'a> {
+    client: &'a mut Client,
+    requests: Vec<Request>,
+}
+
+impl<'a> Pipeline<'a> {
+    /// queue a request
+    pub fn request(mut self, request: Request) -> Self {
+        self.requests.push(request);
+        self
+    }
+
+    /// queue a set request
+    pub fn set(self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> Self {
+        self.request(Request::SET(key.into(), value.into()))
+    }
+
+    /// queue a get request
+    pub fn get(self, key: impl Into<Vec<u8>>) -> Self {
+        self.request(Request::GET(key.into()))
+    }
+
+    /// queue a rm request
+    pub fn remove(self, key: impl Into<Vec<u8>>) -> Self {
+        self.request(Request::RM(key.into()))
+    }
+
+    /// send all queued requests, then read one result per request in the same order.
+    /// The outer error means the connection failed, the inner ones are per request.
+    ///
+    /// Requests are sent in chunks of up to `PIPELINE_WINDOW` bytes, or one larger request,
+    /// and the answers to a chunk are read before the next one is sent. Otherwise both sides
+    /// could block writing to full socket buffers, the server its answers and the client
+    /// its requests.
+    pub fn execute(self) -> Result<Vec<Result<Option<Vec<u8>>>>> {
+        let mut results = Vec::with_capacity(self.requests.len());
+        for (i, request) in self.requests.iter().enumerate() {
+            let connection = &mut self.client.connection;
+            let start = connection.buffered();
+            connection.write(request)?;
+            if start > 0 && connection.buffered() > PIPELINE_WINDOW {
+                connection.flush_until(start)?;
+                while results.len() < i {
+                    results.push(self.client.read_result()?);
+                }
+            }
+        }
+        self.client.connection.flush()?;
+        while results.len() < self.requests.len() {
+            results.push(self.client.read_result()?);
         }
+        Ok(results)
     }
 }
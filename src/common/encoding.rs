@@ -0,0 +1,131 @@
+//! Serde helpers for byte strings.
+//!
+//! Human readable formats (JSON) write valid UTF-8 as a plain string and anything else as an
+//! array of numbers, so logs and requests holding text look exactly as they did when keys and
+//! values were `String`s. Binary formats (bincode) write the raw bytes.
+use serde::de::{self, SeqAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+
+/// a borrowed byte string with the encoding described above
+struct Bytes<'a>(&'a [u8]);
+
+impl Serialize for Bytes<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            match std::str::from_utf8(self.0) {
+                Ok(text) => serializer.serialize_str(text),
+                Err(_) => serializer.collect_seq(self.0),
+            }
+        } else {
+            serializer.serialize_bytes(self.0)
+        }
+    }
+}
+
+/// an owned byte string with the encoding described above
+struct ByteBuf(Vec<u8>);
+
+impl<'de> Deserialize<'de> for ByteBuf {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        if deserializer.is_human_readable() {
+            deserializer.deserialize_any(ByteBufVisitor)
+        } else {
+            deserializer.deserialize_byte_buf(ByteBufVisitor)
+        }
+    }
+}
+
+struct ByteBufVisitor;
+
+impl<'de> Visitor<'de> for ByteBufVisitor {
+    type Value = ByteBuf;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a string or a byte array")
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<ByteBuf, E> {
+        Ok(ByteBuf(v.as_bytes().to_vec()))
+    }
+
+    fn visit_string<E: de::Error>(self, v: String) -> Result<ByteBuf, E> {
+        Ok(ByteBuf(v.into_bytes()))
+    }
+
+    fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<ByteBuf, E> {
+        Ok(ByteBuf(v.to_vec()))
+    }
+
+    fn visit_byte_buf<E: de::Error>(self, v: Vec<u8>) -> Result<ByteBuf, E> {
+        Ok(ByteBuf(v))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<ByteBuf, A::Error> {
+        let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+        while let Some(byte) = seq.next_element()? {
+            bytes.push(byte);
+        }
+        Ok(ByteBuf(bytes))
+    }
+}
+
+/// `#[serde(with = "bytes")]` for `Vec<u8>` fields
+pub(crate) mod bytes {
+    use super::{ByteBuf, Bytes};
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub(crate) fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        Bytes(bytes).serialize(serializer)
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<u8>, D::Error> {
+        ByteBuf::deserialize(deserializer).map(|buf| buf.0)
+    }
+}
+
+/// `#[serde(with = "option_bytes")]` for `Option<Vec<u8>>` fields
+pub(crate) mod option_bytes {
+    use super::{ByteBuf, Bytes};
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub(crate) fn serialize<S: Serializer>(
+        bytes: &Option<Vec<u8>>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        bytes.as_deref().map(Bytes).serialize(serializer)
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Vec<u8>>, D::Error> {
+        Option::<ByteBuf>::deserialize(deserializer).map(|buf| buf.map(|buf| buf.0))
+    }
+}
+
+/// `#[serde(with = "pairs")]` for `Vec<(Vec<u8>, Vec<u8>)>` fields
+pub(crate) mod pairs {
+    use super::{ByteBuf, Bytes};
+    use crate::KvPairs;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub(crate) fn serialize<S: Serializer>(
+        pairs: &[(Vec<u8>, Vec<u8>)],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(pairs.iter().map(|(key, value)| (Bytes(key), Bytes(value))))
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<KvPairs, D::Error> {
+        Vec::<(ByteBuf, ByteBuf)>::deserialize(deserializer).map(|pairs| {
+            pairs
+                .into_iter()
+                .map(|(key, value)| (key.0, value.0))
+                .collect()
+        })
+    }
+}
@@ -1,4 +1,9 @@
+// `#[derive(Fail)]` expands to impls inside an anonymous const
+#![allow(non_local_definitions)]
+use crate::EngineType;
 use failure::Fail;
+use sled::transaction::TransactionError;
+use std::path::PathBuf;
 use std::{io, string};
 
 /// Result type alias for the KVStoreError enum.
@@ -15,6 +20,10 @@ pub enum KVStoreError {
     #[fail(display = "{}", _0)]
     Serde(#[cause] serde_json::Error),
 
+    /// Bincode error
+    #[fail(display = "{}", _0)]
+    Bincode(#[cause] bincode::Error),
+
     /// Sled error
     #[fail(display = "{}", _0)]
     Sled(#[cause] sled::Error),
@@ -31,10 +40,38 @@ pub enum KVStoreError {
     #[fail(display = "Key not found")]
     KeyNotFound,
 
+    /// Compare and swap error, holding the current value of the key
+    #[fail(display = "Compare and swap failed")]
+    CompareAndSwapFailed(Option<Vec<u8>>),
+
     /// Unknown command type error
     #[fail(display = "Unknown command type")]
     UnknownCommandType,
 
+    /// Incomplete batch in the middle of the log error
+    #[fail(display = "Incomplete write batch in data_{}.txt", _0)]
+    IncompleteBatch(u64),
+
+    /// Commit failed error, for writes committed in a group whose commit failed
+    #[fail(display = "Commit failed: {}", _0)]
+    CommitFailed(String),
+
+    /// Corrupted record error, holding the data file number and the offset of the record
+    #[fail(
+        display = "Corrupted record in data_{}.txt at offset {}",
+        file_number, offset
+    )]
+    Corruption {
+        /// number of the damaged data file
+        file_number: u64,
+        /// offset of the damaged record in the file
+        offset: u64,
+    },
+
+    /// Store locked error, for a store directory which another process has opened
+    #[fail(display = "Store {:?} is locked by another process", _0)]
+    StoreLocked(PathBuf),
+
     /// Unknown engine type error
     #[fail(display = "Unknown engine type")]
     UnknownEngineType,
@@ -43,6 +80,38 @@ pub enum KVStoreError {
     #[fail(display = "Change engine after initialization")]
     ChangeEngineError,
 
+    /// Migrated error, for a store whose data was migrated to the given engine
+    #[fail(display = "Store was migrated to {}", _0)]
+    Migrated(EngineType),
+
+    /// Unsupported format version error, for a store written by a newer version
+    #[fail(display = "Unsupported format version {}", _0)]
+    UnsupportedFormatVersion(u32),
+
+    /// Unknown sync policy error
+    #[fail(display = "Unknown sync policy {}", _0)]
+    UnknownSyncPolicy(String),
+
+    /// Unsupported protocol version error
+    #[fail(display = "Unsupported protocol version {}", _0)]
+    UnsupportedProtocol(u8),
+
+    /// TLS error
+    #[fail(display = "{}", _0)]
+    Tls(#[cause] rustls::Error),
+
+    /// Frame too large error
+    #[fail(display = "Frame of {} bytes is too large", _0)]
+    FrameTooLarge(usize),
+
+    /// Permission denied error, holding the reason given by the server
+    #[fail(display = "Permission denied: {}", _0)]
+    PermissionDenied(String),
+
+    /// Unexpected response error
+    #[fail(display = "Unexpected response from server")]
+    UnexpectedResponse,
+
     /// common string error
     #[fail(display = "{}", _0)]
     CommonStringError(String),
@@ -80,6 +149,38 @@ impl From<serde_json::Error> for KVStoreError {
     }
 }
 
+/// Implements the conversion from `bincode::Error` to `KVStoreError`.
+impl From<bincode::Error> for KVStoreError {
+    /// Converts a `bincode::Error` into a `KVStoreError`.
+    ///
+    /// # Arguments
+    ///
+    /// * `err` - The `bincode::Error` to convert.
+    ///
+    /// # Returns
+    ///
+    /// The converted `KVStoreError`.
+    fn from(err: bincode::Error) -> Self {
+        KVStoreError::Bincode(err)
+    }
+}
+
+/// Implements the conversion from `rustls::Error` to `KVStoreError`.
+impl From<rustls::Error> for KVStoreError {
+    /// Converts a `rustls::Error` into a `KVStoreError`.
+    ///
+    /// # Arguments
+    ///
+    /// * `err` - The `rustls::Error` to convert.
+    ///
+    /// # Returns
+    ///
+    /// The converted `KVStoreError`.
+    fn from(err: rustls::Error) -> Self {
+        KVStoreError::Tls(err)
+    }
+}
+
 /// Implements the conversion from `sled::Error` to `KVStoreError`.
 impl From<sled::Error> for KVStoreError {
     /// Converts a `sled::Error` into a `KVStoreError`.
@@ -96,6 +197,26 @@ impl From<sled::Error> for KVStoreError {
     }
 }
 
+/// Implements the conversion from `sled::transaction::TransactionError` to `KVStoreError`.
+impl From<TransactionError<KVStoreError>> for KVStoreError {
+    /// Converts a failed sled transaction into the error it was aborted with,
+    /// or into `KVStoreError::Sled` if the storage failed.
+    ///
+    /// # Arguments
+    ///
+    /// * `err` - The `sled::transaction::TransactionError` to convert.
+    ///
+    /// # Returns
+    ///
+    /// The converted `KVStoreError`.
+    fn from(err: TransactionError<KVStoreError>) -> Self {
+        match err {
+            TransactionError::Abort(err) => err,
+            TransactionError::Storage(err) => KVStoreError::Sled(err),
+        }
+    }
+}
+
 /// Implements the conversion from `std::string::FromUtf8Error` to `KVStoreError`.
 impl From<string::FromUtf8Error> for KVStoreError {
     /// Converts a `std::string::FromUtf8Error` into a `KVStoreError::Utf8Error`.
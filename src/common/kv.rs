@@ -1,19 +1,37 @@
-use crate::{Command, KVStoreError, KvsEngine, Result};
+use crate::common::encoding::bytes;
+use crate::common::{create_snapshot_dir, expires_at, now_millis, Manifest, LEGACY_FORMAT_VERSION};
+use crate::{Command, KVStoreError, KvEntries, KvsEngine, Result, WriteBatch};
+use crate::{EngineType, KvStoreOptions, SyncPolicy};
+use dashmap::mapref::entry::Entry as IndexEntry;
 use dashmap::DashMap;
-use log::{info, warn};
-use serde_json::Deserializer;
-use std::cell::RefCell;
+use fs2::FileExt;
+use log::{error, info, warn};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::cell::{Cell, RefCell};
 use std::collections::hash_map::Entry;
-use std::collections::HashMap;
-use std::fs::{create_dir_all, read_dir, remove_file, File, OpenOptions};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fs::{self, create_dir_all, read_dir, remove_file, rename, File, OpenOptions};
 use std::io;
 use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Take, Write};
-use std::path::PathBuf;
-use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::{Arc, Mutex};
-use std::time::SystemTime;
+use std::marker::PhantomData;
+use std::mem;
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender, SyncSender};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime};
 
-const MAX_USELESS_SIZE: u64 = 1024 * 1024;
+/// version of the on-disk format recorded in the manifest
+const FORMAT_VERSION: u32 = 1;
+/// every record starts with the length and the CRC32 of its payload, as big-endian `u32`s
+const RECORD_HEADER_SIZE: u64 = 8;
+/// the group committer commits at most this many writes at once
+const MAX_GROUP_SIZE: usize = 1024;
+/// scans take at most this many keys from the ordered index at once
+const SCAN_CHUNK_SIZE: usize = 1024;
 
 /** A KvStore stores key/value pairs using BitCask.
 # Example
@@ -37,22 +55,42 @@ assert_eq!(store.get("1".to_owned())?, None);
 #[derive(Clone)]
 /// Represents a key-value store.
 pub struct KvStore {
-    index: Arc<DashMap<String, CommandPosition>>,
+    index: Arc<Index>,
     writer: Arc<Mutex<Writer>>,
     readers: Reader,
+    committer: Option<Arc<GroupCommitter>>,
+    /// keeps the background sync running while any clone of the store is alive
+    _syncer: Option<Arc<IntervalSyncer>>,
 }
 
 impl KvStore {
     /// Open the KvStore at a given path. Return the KvStore.
     pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
+        Self::open_with(path, KvStoreOptions::default())
+    }
+
+    /// Open the KvStore at a given path with the given options. Return the KvStore.
+    pub fn open_with(path: impl Into<PathBuf>, options: KvStoreOptions) -> Result<KvStore> {
         let dir_path = Arc::new(path.into());
         create_dir_all(dir_path.as_path())?;
+        let lock = lock_dir(&dir_path)?;
+        let has_data = !data_file_numbers(&dir_path)?.is_empty();
+        let mut manifest = Manifest::open(
+            &dir_path,
+            EngineType::KvStore,
+            FORMAT_VERSION,
+            &options,
+            has_data,
+        )?;
 
-        let mut index = Arc::new(DashMap::new());
+        let mut index = Arc::new(Index::default());
         let mut readers = HashMap::new();
 
-        let (current_file_number, useless_size) =
-            Self::recover(&dir_path, &mut readers, &mut index)?;
+        let legacy = manifest.format_version == LEGACY_FORMAT_VERSION;
+        let (current_file_number, mut files) =
+            Self::recover(&dir_path, legacy, &mut readers, &mut index)?;
+        manifest.upgrade(&dir_path, FORMAT_VERSION)?;
+        files.entry(current_file_number).or_default();
 
         let current_file_path = dir_path.join(format!("data_{}.txt", current_file_number));
 
@@ -72,108 +110,416 @@ impl KvStore {
 
         let readers = Reader {
             dir_path: Arc::clone(&dir_path),
-            compaction_number: Arc::new(AtomicU64::new(0)),
+            deleted_files: Arc::new(Mutex::new(HashSet::new())),
+            generation: Arc::new(AtomicU64::new(0)),
+            seen_generation: Cell::new(0),
             readers: RefCell::new(readers),
         };
 
         let writer = Arc::new(Mutex::new(Writer {
             current_writer,
             current_file_number,
-            useless_size,
+            files,
+            compaction_threshold: options.compaction_threshold,
+            max_file_size: options.max_file_size,
+            garbage_ratio: options.garbage_ratio,
+            merge_ratio: options.merge_ratio,
             dir_path,
             index: Arc::clone(&index),
             reader: readers.clone(),
+            sync_policy: options.sync_policy,
+            unsynced: 0,
+            staged_data: Vec::new(),
+            staged: Vec::new(),
+            staged_keys: HashMap::new(),
+            compacting: Arc::new(AtomicBool::new(false)),
+            compaction: None,
+            _lock: lock,
         }));
 
+        let committer = options
+            .group_commit
+            .then(|| Arc::new(GroupCommitter::spawn(Arc::clone(&writer))));
+
+        let syncer = match options.sync_policy {
+            SyncPolicy::Interval(interval) => Some(Arc::new(IntervalSyncer::spawn(
+                Arc::clone(&writer),
+                interval,
+            ))),
+            _ => None,
+        };
+
         Ok(KvStore {
             readers,
             writer,
             index,
+            committer,
+            _syncer: syncer,
         })
     }
 
+    /// Compact the data files now, without waiting for the compaction triggers.
+    ///
+    /// Writes continue while the compaction runs, but are not compacted by it. Return once the
+    /// compaction is finished, or the error it failed with.
+    pub fn compact(&self) -> Result<()> {
+        self.run_compaction(None)
+    }
+
+    /// Merge only the data files with the given numbers, like `compact` does with all of them.
+    /// Numbers of files which do not exist are ignored.
+    pub fn compact_files(&self, file_numbers: &[u64]) -> Result<()> {
+        self.run_compaction(Some(file_numbers))
+    }
+
+    /// Return the live and dead bytes of every data file by file number.
+    pub fn file_stats(&self) -> BTreeMap<u64, FileStats> {
+        self.writer.lock().unwrap().files.clone()
+    }
+
+    fn run_compaction(&self, sources: Option<&[u64]>) -> Result<()> {
+        let mut writer = self.idle_writer();
+        writer.compact(sources)?;
+        // nothing to compact if no handle was left
+        let handle = match writer.compaction.take() {
+            Some(handle) => handle,
+            None => return Ok(()),
+        };
+        drop(writer);
+        let result = handle.join();
+        self.writer.lock().unwrap().compaction_finished(result)
+    }
+
+    /// Lock the writer once no compaction is running, waiting for it without blocking writes.
+    fn idle_writer(&self) -> MutexGuard<'_, Writer> {
+        loop {
+            let mut writer = self.writer.lock().unwrap();
+            if !writer.is_compacting() {
+                return writer;
+            }
+            let handle = writer.compaction.take();
+            drop(writer);
+            match handle {
+                Some(handle) => {
+                    let result = handle.join();
+                    if let Err(err) = self.writer.lock().unwrap().compaction_finished(result) {
+                        error!("compaction failed: {}", err);
+                    }
+                }
+                // another caller is waiting for it
+                None => thread::sleep(Duration::from_millis(10)),
+            }
+        }
+    }
+
+    /// Hand a write to the group committer, or commit it alone under the writer lock.
+    fn write(&self, op: WriteOp) -> Result<()> {
+        match &self.committer {
+            Some(committer) => committer.write(op),
+            None => self.writer.lock().unwrap().write(op),
+        }
+    }
+
+    /// Rebuild the index from the data files. The files of a `legacy` store which were written
+    /// before records had a header are converted first.
     fn recover(
         dir_path: &Arc<PathBuf>,
+        legacy: bool,
         current_readers: &mut HashMap<u64, BufReader<File>>,
-        index: &mut Arc<DashMap<String, CommandPosition>>,
-    ) -> Result<(u64, u64)> {
-        let mut versions: Vec<u64> = read_dir(dir_path.as_path())?
-            .flat_map(|res| res.map(|e| e.path()))
-            .filter(|path| path.is_file() && path.extension() == Some("txt".as_ref()))
-            .flat_map(|path| {
-                path.file_name()
-                    .and_then(|filename| filename.to_str())
-                    .map(|filename| {
-                        filename
-                            .trim_start_matches("data_")
-                            .trim_end_matches(".txt")
-                    })
-                    .map(str::parse::<u64>)
-            })
-            .flatten()
-            .collect();
-        versions.sort();
+        index: &mut Arc<Index>,
+    ) -> Result<(u64, BTreeMap<u64, FileStats>)> {
+        // output of a compaction or conversion which was interrupted before it was complete
+        for path in read_dir(dir_path.as_path())?.flat_map(|res| res.map(|e| e.path())) {
+            if path.is_file()
+                && (path.extension() == Some("compact".as_ref())
+                    || path.extension() == Some("upgrade".as_ref()))
+            {
+                warn!("remove unfinished output {:?}", path);
+                remove_file(&path)?;
+            }
+        }
 
-        let mut useless_size = 0;
+        let versions = data_file_numbers(dir_path)?;
+        // hint of a data file which a compaction deleted right before a crash
+        for path in read_dir(dir_path.as_path())?.flat_map(|res| res.map(|e| e.path())) {
+            if path.is_file()
+                && path.extension() == Some("hint".as_ref())
+                && !path.with_extension("txt").exists()
+            {
+                remove_file(&path)?;
+            }
+        }
+
+        let mut files = BTreeMap::new();
+        let last_version = versions.last().copied();
         for version in &versions {
             let file_path = dir_path.join(format!("data_{}.txt", version));
-            let reader = BufReader::new(File::open(&file_path)?);
-            let mut iter = Deserializer::from_reader(reader).into_iter::<Command>();
-            let mut before_offset = iter.byte_offset() as u64;
-            while let Some(command) = iter.next() {
-                let after_offset = iter.byte_offset() as u64;
-                match command? {
-                    Command::SET(key, _) => {
-                        useless_size += index
-                            .insert(
-                                key,
-                                CommandPosition {
-                                    offset: before_offset,
-                                    length: after_offset - before_offset,
-                                    file_number: *version,
-                                },
-                            )
-                            .map(|cp| cp.length)
-                            .unwrap_or(0);
+            let is_active = Some(*version) == last_version;
+            if is_legacy_file(&file_path)? {
+                if !legacy {
+                    return Err(KVStoreError::Corruption {
+                        file_number: *version,
+                        offset: 0,
+                    });
+                }
+                upgrade_legacy_file(&file_path, *version, is_active)?;
+            }
+            files.insert(*version, FileStats::default());
+            if let Some(entries) = load_hint(&file_path, *version)? {
+                for entry in entries {
+                    let position = CommandPosition {
+                        offset: entry.offset,
+                        length: entry.length,
+                        file_number: entry.file_number,
+                        expires_at: entry.expires_at,
+                    };
+                    if entry.removed {
+                        apply_to_index(index, &mut files, Command::RM(entry.key), position);
+                    } else {
+                        insert_to_index(index, &mut files, entry.key, position);
                     }
-                    Command::RM(key) => {
-                        useless_size += index.remove(&key).map(|(_, cp)| cp.length).unwrap_or(0);
-                        useless_size += after_offset - before_offset;
+                }
+                add_total(&mut files, *version, fs::metadata(&file_path)?.len());
+                current_readers.insert(*version, BufReader::new(File::open(&file_path)?));
+                continue;
+            }
+
+            let mut records = RecordReader::open(&file_path, *version, is_active)?;
+            let mut torn_offset = None;
+            while let Some((command, position)) = records.next()? {
+                let count = match command {
+                    Command::BATCH(count) => count,
+                    command => {
+                        apply_to_index(index, &mut files, command, position);
+                        continue;
                     }
                 };
-                before_offset = after_offset;
+
+                // a batch is only applied if all of its commands made it to disk
+                let mut batch = Vec::with_capacity(count);
+                while batch.len() < count {
+                    match records.next()? {
+                        Some(record) => batch.push(record),
+                        None => break,
+                    }
+                }
+                if batch.len() < count {
+                    if !is_active {
+                        return Err(KVStoreError::IncompleteBatch(*version));
+                    }
+                    warn!(
+                        "drop incomplete batch at the end of {:?}, offset {}",
+                        file_path, position.offset
+                    );
+                    torn_offset = Some(position.offset);
+                    break;
+                }
+                add_dead(&mut files, &position);
+                for (command, position) in batch {
+                    apply_to_index(index, &mut files, command, position);
+                }
+            }
+            if let Some(length) = torn_offset.or(records.torn_offset) {
+                OpenOptions::new()
+                    .write(true)
+                    .open(&file_path)?
+                    .set_len(length)?;
             }
+            add_total(&mut files, *version, fs::metadata(&file_path)?.len());
             current_readers.insert(*version, BufReader::new(File::open(&file_path)?));
         }
 
-        Ok((*versions.last().unwrap_or(&0), useless_size))
+        Ok((*versions.last().unwrap_or(&0), files))
     }
 }
 
 impl KvsEngine for KvStore {
-    /// Set the value of a string key to a string. Return an error if the value is not written successfully.
-    fn set(&self, key: String, value: String) -> Result<()> {
-        self.writer.lock().unwrap().set(key, value)
+    /// Set the value of a key. Return an error if the value is not written successfully.
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.write(WriteOp::Set(key, value, None))
     }
 
-    /// Get the string value of a string key. If the key does not exist, return None. Return an error if the value is not read successfully.
-    fn get(&self, key: String) -> Result<Option<String>> {
-        if let Some(entry) = self.index.get(&key) {
-            self.readers.read_command(entry.value())
-        } else {
-            Ok(None)
+    /// Set the value of a key with its expiry logged in the `SET` record.
+    fn set_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        self.write(WriteOp::Set(key, value, Some(expires_at(ttl))))
+    }
+
+    /// Get the value of a key. If the key does not exist or has expired, return None. Return an error if the value is not read successfully.
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        read_value(&self.index, &self.readers, key)
+    }
+
+    /// Return key/value pairs in `[start, end)` in key order, with the ttl left of each.
+    /// Keys are taken from the ordered keys of the index a chunk at a time, so only about
+    /// `limit` of them are looked at.
+    fn scan_with_ttl(
+        &self,
+        start: &[u8],
+        end: Option<&[u8]>,
+        limit: Option<usize>,
+    ) -> Result<KvEntries> {
+        let limit = limit.unwrap_or(usize::MAX);
+        let mut pairs = Vec::new();
+        if end.is_some_and(|end| end <= start) {
+            return Ok(pairs);
         }
+        let mut from = Bound::Included(start.to_vec());
+        let end = end.map_or(Bound::Unbounded, |end| Bound::Excluded(end.to_vec()));
+        while pairs.len() < limit {
+            let chunk_size = (limit - pairs.len()).min(SCAN_CHUNK_SIZE);
+            let keys = self.index.keys_in((from, end.clone()), chunk_size);
+            let last = match keys.last() {
+                Some(last) => last.clone(),
+                None => break,
+            };
+            for key in keys {
+                // the key may have expired or been removed since it was taken
+                if let Some((value, expires_at)) = read_entry(&self.index, &self.readers, &key)? {
+                    let ttl = expires_at.map(|expires_at| {
+                        Duration::from_millis(expires_at.saturating_sub(now_millis()))
+                    });
+                    pairs.push((key, value, ttl));
+                }
+            }
+            from = Bound::Excluded(last);
+        }
+        Ok(pairs)
     }
 
     /// Remove a given key. Return an error if the key does not exist or is not removed successfully.
-    fn remove(&self, key: String) -> Result<()> {
-        self.writer.lock().unwrap().remove(key)
+    fn remove_bytes(&self, key: &[u8]) -> Result<()> {
+        self.write(WriteOp::Remove(key.to_vec()))
+    }
+
+    /// Compare and swap in the writer, so no other write can interleave.
+    fn compare_and_swap(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<()> {
+        self.write(WriteOp::CompareAndSwap(key, expected, new))
+    }
+
+    /// Log the batch as one framed record and apply it to the index.
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        self.write(WriteOp::Batch(batch))
+    }
+
+    /// Compact the data files and wait for the compaction to finish.
+    fn compact(&self) -> Result<()> {
+        KvStore::compact(self)
+    }
+
+    /// Sync the active file, the only one with writes which may not be on disk yet.
+    fn flush(&self) -> Result<()> {
+        self.writer.lock().unwrap().sync_pending()
+    }
+
+    /// Seal the active file and hard-link every data file into `dest`, copying those which
+    /// can not be linked, while writes wait. A running compaction is waited for first.
+    fn snapshot(&self, dest: &Path) -> Result<()> {
+        create_snapshot_dir(dest)?;
+        self.idle_writer().snapshot(dest)
+    }
+}
+
+/// How many bytes of a data file are live, i.e. still pointed to by the index, and how many
+/// are dead: overwritten or removed records, removals and batch headers.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FileStats {
+    /// size of the records in the file
+    pub total_bytes: u64,
+    /// size of the dead records in the file
+    pub dead_bytes: u64,
+}
+
+impl FileStats {
+    /// Return the size of the live records in the file.
+    pub fn live_bytes(&self) -> u64 {
+        self.total_bytes.saturating_sub(self.dead_bytes)
+    }
+
+    /// Return the share of the file taken by dead records, 0.0 for an empty file.
+    pub fn dead_ratio(&self) -> f64 {
+        if self.total_bytes == 0 {
+            return 0.0;
+        }
+        self.dead_bytes as f64 / self.total_bytes as f64
+    }
+}
+
+/// The index of a `KvStore`: the position of the latest record of every key, and the same keys
+/// in order for scans.
+#[derive(Default)]
+struct Index {
+    positions: DashMap<Vec<u8>, CommandPosition>,
+    /// the keys of `positions` in order. It is only changed while the shard of `positions`
+    /// holding the key is locked, and never locked while a shard is, so both agree on the keys.
+    keys: RwLock<BTreeSet<Vec<u8>>>,
+}
+
+impl Index {
+    fn get(&self, key: &[u8]) -> Option<CommandPosition> {
+        self.positions.get(key).map(|entry| *entry.value())
+    }
+
+    fn contains_key(&self, key: &[u8]) -> bool {
+        self.positions.contains_key(key)
+    }
+
+    /// Point a key to a record. Return the position it pointed to before.
+    fn insert(&self, key: Vec<u8>, position: CommandPosition) -> Option<CommandPosition> {
+        match self.positions.entry(key) {
+            IndexEntry::Occupied(mut entry) => Some(entry.insert(position)),
+            IndexEntry::Vacant(entry) => {
+                self.keys.write().unwrap().insert(entry.key().clone());
+                entry.insert(position);
+                None
+            }
+        }
+    }
+
+    fn remove(&self, key: &[u8]) -> Option<CommandPosition> {
+        self.remove_if(key, |_| true)
+    }
+
+    /// Remove a key if its position satisfies `f`. Return the position it pointed to.
+    fn remove_if(
+        &self,
+        key: &[u8],
+        f: impl FnOnce(&CommandPosition) -> bool,
+    ) -> Option<CommandPosition> {
+        match self.positions.entry(key.to_vec()) {
+            IndexEntry::Occupied(entry) if f(entry.get()) => {
+                self.keys.write().unwrap().remove(key);
+                Some(entry.remove())
+            }
+            _ => None,
+        }
+    }
+
+    /// Return up to `limit` keys in the range, in order, expired ones included.
+    fn keys_in(&self, range: (Bound<Vec<u8>>, Bound<Vec<u8>>), limit: usize) -> Vec<Vec<u8>> {
+        self.keys
+            .read()
+            .unwrap()
+            .range(range)
+            .take(limit)
+            .cloned()
+            .collect()
     }
 }
 
 struct Reader {
     dir_path: Arc<PathBuf>,
-    compaction_number: Arc<AtomicU64>,
+    /// numbers of the files which compactions merged and deleted. File numbers are never
+    /// reused, so no reader may keep any of them open.
+    deleted_files: Arc<Mutex<HashSet<u64>>>,
+    /// bumped whenever files are added to `deleted_files`
+    generation: Arc<AtomicU64>,
+    /// the generation this clone last closed the readers of deleted files at
+    seen_generation: Cell<u64>,
     readers: RefCell<HashMap<u64, BufReader<File>>>,
 }
 
@@ -181,23 +527,33 @@ impl Clone for Reader {
     fn clone(&self) -> Self {
         Reader {
             dir_path: Arc::clone(&self.dir_path),
-            compaction_number: Arc::clone(&self.compaction_number),
+            deleted_files: Arc::clone(&self.deleted_files),
+            generation: Arc::clone(&self.generation),
+            seen_generation: Cell::new(0),
             readers: RefCell::new(HashMap::new()),
         }
     }
 }
 
 impl Reader {
+    /// Close the readers of files which finished compactions deleted since the last check.
     fn try_to_remove_stale_readers(&self) {
-        let compaction_number = self.compaction_number.load(Ordering::SeqCst);
-        let mut readers = self.readers.borrow_mut();
-        while !readers.is_empty() {
-            let reader_number = *readers.keys().next().unwrap();
-            if compaction_number <= reader_number {
-                break;
-            }
-            readers.remove(&reader_number);
+        let generation = self.generation.load(Ordering::SeqCst);
+        if generation == self.seen_generation.get() {
+            return;
         }
+        let deleted_files = self.deleted_files.lock().unwrap();
+        self.readers
+            .borrow_mut()
+            .retain(|reader_number, _| !deleted_files.contains(reader_number));
+        self.seen_generation.set(generation);
+    }
+
+    /// Record that the given files are about to be deleted, so every clone closes its readers
+    /// of them.
+    fn remove_files(&self, numbers: &[u64]) {
+        self.deleted_files.lock().unwrap().extend(numbers);
+        self.generation.fetch_add(1, Ordering::SeqCst);
     }
 
     fn read_add<F, R>(&self, position: &CommandPosition, f: F) -> Result<R>
@@ -224,12 +580,14 @@ impl Reader {
         f(data_reader)
     }
 
-    fn read_command(&self, position: &CommandPosition) -> Result<Option<String>> {
-        self.read_add(position, |data_reader| {
-            if let Command::SET(_, value) = serde_json::from_reader(data_reader)? {
-                Ok(Some(value))
-            } else {
-                Err(KVStoreError::UnknownCommandType)
+    fn read_command(&self, position: &CommandPosition) -> Result<Option<Vec<u8>>> {
+        self.read_add(position, |mut data_reader| {
+            let mut record = Vec::with_capacity(position.length as usize);
+            data_reader.read_to_end(&mut record)?;
+            match decode_record(&record) {
+                Some(Command::SET(_, value, _)) => Ok(Some(value)),
+                Some(_) => Err(KVStoreError::UnknownCommandType),
+                None => Err(position.corruption()),
             }
         })
     }
@@ -240,29 +598,23 @@ impl Reader {
         writer: &mut BufWriterWithPosition<File>,
     ) -> Result<()> {
         self.read_add(position, |mut data_reader| {
-            io::copy(&mut data_reader, writer)?;
+            let mut record = Vec::with_capacity(position.length as usize);
+            data_reader.read_to_end(&mut record)?;
+            if !check_record(&record) {
+                return Err(position.corruption());
+            }
+            writer.write_all(&record)?;
             Ok(())
         })
     }
+}
 
-    fn remove_useless_reader(&mut self, file_number: u64) -> Result<()> {
-        let mut readers = self.readers.borrow_mut();
-        let delete_file_numbers: Vec<u64> = readers
-            .iter()
-            .map(|(key, _)| *key)
-            .filter(|key| *key < file_number)
-            .collect();
-
-        for number in delete_file_numbers {
-            readers.remove(&number);
-            let file_path = self.dir_path.join(format!("data_{}.txt", number));
-            if let Err(err) = remove_file(&file_path) {
-                warn!("can not delete file {:?} because {}", file_path, err);
-            }
-        }
-
-        Ok(())
-    }
+/// A write submitted to the `Writer`.
+enum WriteOp {
+    Set(Vec<u8>, Vec<u8>, Option<u64>),
+    Remove(Vec<u8>),
+    Batch(WriteBatch),
+    CompareAndSwap(Vec<u8>, Option<Vec<u8>>, Option<Vec<u8>>),
 }
 
 struct Writer {
@@ -270,115 +622,961 @@ struct Writer {
     reader: Reader,
     current_writer: BufWriterWithPosition<File>,
     current_file_number: u64,
-    useless_size: u64,
-    index: Arc<DashMap<String, CommandPosition>>,
+    /// live and dead bytes of every data file
+    files: BTreeMap<u64, FileStats>,
+    compaction_threshold: u64,
+    garbage_ratio: f64,
+    /// automatic compactions merge the files with at least this ratio of dead bytes
+    merge_ratio: f64,
+    /// the active file is sealed and a new one opened once it grows to this size
+    max_file_size: u64,
+    index: Arc<Index>,
+    sync_policy: SyncPolicy,
+    /// number of commits since the active file was last synced
+    unsynced: u64,
+    /// encoded records of the staged commands
+    staged_data: Vec<u8>,
+    /// staged commands with the length of their records
+    staged: Vec<(Command, u64)>,
+    /// index into `staged` of the last staged command of each key
+    staged_keys: HashMap<Vec<u8>, usize>,
+    /// set while a compaction runs
+    compacting: Arc<AtomicBool>,
+    /// the running or last finished background compaction
+    compaction: Option<JoinHandle<Result<Compacted>>>,
+    /// keeps other processes from opening the store until the writer is dropped
+    _lock: File,
 }
 
 impl Writer {
-    fn set(&mut self, key: String, value: String) -> Result<()> {
-        let command = Command::SET(key, value);
-        let data = serde_json::to_vec(&command)?;
+    /// Stage a write and commit it on its own.
+    fn write(&mut self, op: WriteOp) -> Result<()> {
+        self.stage(op)?;
+        self.commit()
+    }
+
+    /// Check a write against the index and the writes staged before it, then stage its
+    /// commands for the next commit. A write which fails its check stages nothing.
+    fn stage(&mut self, op: WriteOp) -> Result<()> {
+        let commands = match op {
+            WriteOp::Set(key, value, expires_at) => vec![Command::SET(key, value, expires_at)],
+            WriteOp::Remove(key) => {
+                if !self.contains(&key) {
+                    return Err(KVStoreError::KeyNotFound);
+                }
+                vec![Command::RM(key)]
+            }
+            WriteOp::Batch(batch) => batch.ops.into_iter().map(Command::from).collect(),
+            WriteOp::CompareAndSwap(key, expected, new) => {
+                let current = self.current_value(&key)?;
+                if current != expected {
+                    return Err(KVStoreError::CompareAndSwapFailed(current));
+                }
+                match new {
+                    Some(value) => vec![Command::SET(key, value, None)],
+                    None if current.is_some() => vec![Command::RM(key)],
+                    None => Vec::new(),
+                }
+            }
+        };
+        if commands.len() > 1 {
+            self.stage_command(Command::BATCH(commands.len()))?;
+        }
+        for command in commands {
+            self.stage_command(command)?;
+        }
+        Ok(())
+    }
+
+    fn stage_command(&mut self, command: Command) -> Result<()> {
+        let before = self.staged_data.len();
+        encode_record(&command, &mut self.staged_data)?;
+        let length = (self.staged_data.len() - before) as u64;
+        if let Command::SET(key, _, _) | Command::RM(key) = &command {
+            self.staged_keys.insert(key.clone(), self.staged.len());
+        }
+        self.staged.push((command, length));
+        Ok(())
+    }
+
+    /// Return true if the key exists once the staged writes are applied.
+    fn contains(&self, key: &[u8]) -> bool {
+        match self.staged_keys.get(key) {
+            Some(&i) => matches!(self.staged[i].0, Command::SET(..)),
+            None => self
+                .index
+                .get(key)
+                .is_some_and(|position| !position.is_expired(now_millis())),
+        }
+    }
 
-        let offset = self.current_writer.get_position();
+    /// Return the value of a key once the staged writes are applied.
+    fn current_value(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        if let Some(&i) = self.staged_keys.get(key) {
+            return match &self.staged[i].0 {
+                Command::SET(_, value, _) => Ok(Some(value.clone())),
+                _ => Ok(None),
+            };
+        }
+        read_value(&self.index, &self.reader, key)
+    }
+
+    /// Write the staged records to the active file with a single flush, sync them if the sync
+    /// policy asks for it, then apply them to the index. Several commands of one write are
+    /// framed by a `BATCH` header so recovery applies all or none of them.
+    fn commit(&mut self) -> Result<()> {
+        if self.staged.is_empty() {
+            return Ok(());
+        }
+        let data = mem::take(&mut self.staged_data);
+        let staged = mem::take(&mut self.staged);
+        self.staged_keys.clear();
+
+        let mut offset = self.current_writer.get_position();
         self.current_writer.write_all(&data)?;
         self.current_writer.flush()?;
-        let length = self.current_writer.get_position() - offset;
-        let file_number = self.current_file_number;
+        add_total(&mut self.files, self.current_file_number, data.len() as u64);
+        self.unsynced += 1;
+        match self.sync_policy {
+            SyncPolicy::EveryWrite => self.sync_pending()?,
+            SyncPolicy::EveryN(n) if self.unsynced >= n => self.sync_pending()?,
+            _ => {}
+        }
 
-        if let Command::SET(key, _) = command {
-            self.useless_size += self
-                .index
-                .insert(
-                    key,
-                    CommandPosition {
-                        offset,
-                        length,
-                        file_number,
-                    },
-                )
-                .map(|cp| cp.length)
-                .unwrap_or(0);
+        for (command, length) in staged {
+            let position = CommandPosition {
+                offset,
+                length,
+                file_number: self.current_file_number,
+                expires_at: None,
+            };
+            apply_to_index(&self.index, &mut self.files, command, position);
+            offset += length;
         }
 
-        if self.useless_size > MAX_USELESS_SIZE {
-            let now = SystemTime::now();
-            info!("Compaction starts");
-            self.compact()?;
-            info!("Compaction finished, cost {:?}", now.elapsed());
+        if self
+            .compaction
+            .as_ref()
+            .is_some_and(JoinHandle::is_finished)
+        {
+            self.finish_compaction();
+        }
+        if self.current_writer.get_position() >= self.max_file_size {
+            self.open_active_file(self.current_file_number + 1)?;
+            self.files.entry(self.current_file_number).or_default();
+        }
+        if self.needs_compaction() && !self.is_compacting() {
+            let sources: Vec<u64> = self
+                .files
+                .iter()
+                .filter(|(_, stats)| stats.dead_ratio() >= self.merge_ratio)
+                .map(|(&number, _)| number)
+                .collect();
+            self.compact(Some(&sources))?;
         }
 
         Ok(())
     }
 
-    fn remove(&mut self, key: String) -> Result<()> {
-        if self.index.get(&key).is_some() {
-            self.useless_size += self
-                .index
-                .remove(&key)
-                .map(|(_, cp)| cp.length)
-                .unwrap_or(0);
+    /// Seal the active file and merge the given files, or all files if None, on a background
+    /// thread. Nothing is started if none of the given files exists.
+    ///
+    /// The compacted file takes the next file number and new writes go to a fresh active file
+    /// after it. So on recovery the copied data is replayed after every file it did not merge,
+    /// and writes made during the compaction are replayed after the copied data.
+    fn compact(&mut self, sources: Option<&[u64]>) -> Result<()> {
+        self.finish_compaction();
+        let (sources, surviving): (Vec<u64>, Vec<u64>) = self
+            .files
+            .keys()
+            .partition(|number| sources.is_none_or(|sources| sources.contains(number)));
+        if sources.is_empty() {
+            return Ok(());
+        }
+        self.compacting.store(true, Ordering::SeqCst);
+
+        let compaction = Compaction {
+            dir_path: Arc::clone(&self.dir_path),
+            reader: self.reader.clone(),
+            index: Arc::clone(&self.index),
+            file_number: self.current_file_number + 1,
+            sources,
+            oldest_surviving: surviving.first().copied(),
+        };
+        self.open_active_file(compaction.file_number + 1)?;
+        self.files.entry(compaction.file_number + 1).or_default();
+
+        let running = CompactionRunning(Arc::clone(&self.compacting));
+        self.compaction = Some(thread::spawn(move || {
+            let _running = running;
+            compaction.run()
+        }));
+        Ok(())
+    }
 
-            let command = serde_json::to_vec(&Command::RM(key))?;
-            let offset = self.current_writer.get_position();
-            self.current_writer.write_all(&command)?;
-            self.current_writer.flush()?;
+    /// Return true if the garbage in the data files exceeds both the compaction threshold and
+    /// the garbage ratio.
+    fn needs_compaction(&self) -> bool {
+        let dead_bytes: u64 = self.files.values().map(|stats| stats.dead_bytes).sum();
+        let total_bytes: u64 = self.files.values().map(|stats| stats.total_bytes).sum();
+        dead_bytes > self.compaction_threshold
+            && dead_bytes as f64 >= self.garbage_ratio * total_bytes as f64
+    }
 
-            self.useless_size += self.current_writer.get_position() - offset;
+    fn is_compacting(&self) -> bool {
+        self.compacting.load(Ordering::SeqCst)
+    }
 
-            if self.useless_size > MAX_USELESS_SIZE {
-                self.compact()?;
+    /// Record the outcome of a finished compaction. Return the error it failed with.
+    ///
+    /// Records which writes made dead during the compaction are already counted against the
+    /// compacted file.
+    fn compaction_finished(&mut self, result: thread::Result<Result<Compacted>>) -> Result<()> {
+        match result {
+            Ok(Ok(compacted)) => {
+                for number in &compacted.sources {
+                    self.files.remove(number);
+                }
+                let stats = self.files.entry(compacted.file_number).or_default();
+                stats.total_bytes += compacted.stats.total_bytes;
+                stats.dead_bytes += compacted.stats.dead_bytes;
+                Ok(())
             }
+            Ok(Err(err)) => Err(err),
+            Err(_) => Err(KVStoreError::CommonStringError(
+                "compaction thread panicked".to_owned(),
+            )),
+        }
+    }
 
-            Ok(())
-        } else {
-            Err(KVStoreError::KeyNotFound)
+    /// Wait for the last compaction started by the writer, if any, and log its failure.
+    fn finish_compaction(&mut self) {
+        if let Some(handle) = self.compaction.take() {
+            if let Err(err) = self.compaction_finished(handle.join()) {
+                error!("compaction failed: {}", err);
+            }
         }
     }
 
-    fn compact(&mut self) -> Result<()> {
-        self.create_new_file()?;
+    /// Seal the active file, then put every data file and hint into `dest`. Sealed files are
+    /// never written again, so they can be shared with the snapshot through hard links.
+    fn snapshot(&mut self, dest: &Path) -> Result<()> {
+        self.finish_compaction();
+        let sealed = self.current_file_number;
+        self.open_active_file(sealed + 1)?;
+        self.files.entry(sealed + 1).or_default();
 
-        let mut before_offset = 0;
-        for mut entry in self.index.iter_mut() {
-            let position = entry.value_mut();
-            self.reader
-                .copy_data_to_writer(position, &mut self.current_writer)?;
-            let after_offset = self.current_writer.position;
-            *position = CommandPosition {
-                offset: before_offset,
-                length: after_offset - before_offset,
-                file_number: self.current_file_number,
-            };
-            before_offset = after_offset;
+        for &number in self.files.keys().filter(|&&number| number <= sealed) {
+            for extension in ["txt", "hint"] {
+                let name = format!("data_{}.{}", number, extension);
+                let source = self.dir_path.join(&name);
+                if source.exists() && fs::hard_link(&source, dest.join(&name)).is_err() {
+                    fs::copy(&source, dest.join(&name))?;
+                }
+            }
         }
-        self.current_writer.flush()?;
+        // the snapshot appends to a file of its own once it is opened
+        File::create(dest.join(format!("data_{}.txt", sealed + 1)))?;
+        if let Some(manifest) = Manifest::load(self.dir_path.as_path())? {
+            manifest.store(dest)?;
+        }
+        Ok(())
+    }
 
-        self.reader
-            .compaction_number
-            .store(self.current_file_number, Ordering::SeqCst);
+    /// Sync the active file if writes were appended since the last sync.
+    fn sync_pending(&mut self) -> Result<()> {
+        if self.unsynced > 0 {
+            self.current_writer.sync_data()?;
+            self.unsynced = 0;
+        }
+        Ok(())
+    }
 
-        self.reader
-            .remove_useless_reader(self.current_file_number)?;
+    fn open_active_file(&mut self, file_number: u64) -> Result<()> {
+        self.sync_pending()?;
+        self.current_writer = BufWriterWithPosition::new(
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(self.dir_path.join(format!("data_{}.txt", file_number)))?,
+        )?;
+        self.current_file_number = file_number;
+        Ok(())
+    }
+}
 
-        self.useless_size = 0;
+impl Drop for Writer {
+    fn drop(&mut self) {
+        self.finish_compaction();
+    }
+}
 
-        self.create_new_file()?;
+/// Clears the compacting flag when the compaction thread ends, even if it panics.
+struct CompactionRunning(Arc<AtomicBool>);
 
-        Ok(())
+impl Drop for CompactionRunning {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::SeqCst);
     }
+}
 
-    fn create_new_file(&mut self) -> Result<()> {
-        self.current_file_number += 1;
-        self.current_writer = BufWriterWithPosition::new(
-            OpenOptions::new().create(true).append(true).open(
-                self.dir_path
-                    .join(format!("data_{}.txt", self.current_file_number)),
-            )?,
+/// The files a finished compaction merged, and the file it merged them into.
+struct Compacted {
+    sources: Vec<u64>,
+    file_number: u64,
+    /// bytes of the compacted file, those not copied into the index being dead
+    stats: FileStats,
+}
+
+/// Copies the live entries of the sealed `sources` files into the compacted file
+/// `data_<file_number>.txt`.
+///
+/// Removals in the sources are kept as well if an older file survives the compaction, since
+/// the removed keys may still be set in it.
+struct Compaction {
+    dir_path: Arc<PathBuf>,
+    reader: Reader,
+    index: Arc<Index>,
+    file_number: u64,
+    /// numbers of the merged files, in ascending order
+    sources: Vec<u64>,
+    /// number of the oldest file which is not merged
+    oldest_surviving: Option<u64>,
+}
+
+impl Compaction {
+    /// Copy the entries into a temporary file, which is synced and renamed once complete.
+    /// Then point every index entry which was not overwritten meanwhile to its copy, and
+    /// delete the merged files.
+    fn run(self) -> Result<Compacted> {
+        let start = SystemTime::now();
+        info!("Compaction into data_{}.txt starts", self.file_number);
+
+        let mut entries: Vec<(Vec<u8>, CommandPosition)> = self
+            .index
+            .positions
+            .iter()
+            .filter(|entry| {
+                self.sources
+                    .binary_search(&entry.value().file_number)
+                    .is_ok()
+            })
+            .map(|entry| (entry.key().clone(), *entry.value()))
+            .collect();
+        entries.sort_unstable_by_key(|(_, position)| (position.file_number, position.offset));
+
+        let temp_path = self
+            .dir_path
+            .join(format!("data_{}.compact", self.file_number));
+        let mut writer = BufWriterWithPosition::new(
+            OpenOptions::new()
+                .create(true)
+                .write(true)
+                .truncate(true)
+                .open(&temp_path)?,
         )?;
+        let now = now_millis();
+        let mut copies = Vec::with_capacity(entries.len());
+        let mut removed = HashSet::new();
+        for (key, position) in entries {
+            if position.is_expired(now) {
+                let expired = self
+                    .index
+                    .remove_if(&key, |current| current.same_record(&position));
+                if expired.is_some() && self.shadows_older(position.file_number) {
+                    removed.insert(key);
+                }
+                continue;
+            }
+            let offset = writer.get_position();
+            self.reader.copy_data_to_writer(&position, &mut writer)?;
+            let copy = CommandPosition {
+                offset,
+                length: writer.get_position() - offset,
+                file_number: self.file_number,
+                expires_at: position.expires_at,
+            };
+            copies.push((key, position, copy));
+        }
+        self.find_removals(&mut removed)?;
+        let mut tombstones = Vec::new();
+        for key in removed {
+            let offset = writer.get_position();
+            let mut record = Vec::new();
+            encode_record(&Command::RM(key.clone()), &mut record)?;
+            writer.write_all(&record)?;
+            let tombstone = CommandPosition {
+                offset,
+                length: record.len() as u64,
+                file_number: self.file_number,
+                expires_at: None,
+            };
+            tombstones.push((key, tombstone));
+        }
+        // the compacted file must be on disk before the files it replaces are deleted
+        writer.sync_data()?;
+        let compacted_size = writer.get_position();
+        let file_path = self.dir_path.join(format!("data_{}.txt", self.file_number));
+        rename(&temp_path, &file_path)?;
+        // without a hint the compacted file is recovered by scanning it
+        if let Err(err) = write_hint(&file_path, &copies, &tombstones) {
+            warn!("can not write hint for {:?} because {}", file_path, err);
+        }
+
+        // copies of overwritten entries and the removals are dead from the start
+        let mut live_bytes = 0;
+        for (key, position, copy) in copies {
+            if let Some(mut entry) = self.index.positions.get_mut(&key) {
+                if entry.same_record(&position) {
+                    *entry = copy;
+                    live_bytes += copy.length;
+                }
+            }
+        }
+
+        self.reader.remove_files(&self.sources);
+        for &number in &self.sources {
+            let file_path = self.dir_path.join(format!("data_{}.txt", number));
+            if let Err(err) = remove_file(&file_path) {
+                warn!("can not delete file {:?} because {}", file_path, err);
+            }
+            let hint_path = file_path.with_extension("hint");
+            if hint_path.exists() {
+                if let Err(err) = remove_file(&hint_path) {
+                    warn!("can not delete file {:?} because {}", hint_path, err);
+                }
+            }
+        }
+
+        info!("Compaction finished, cost {:?}", start.elapsed());
+        Ok(Compacted {
+            sources: self.sources,
+            file_number: self.file_number,
+            stats: FileStats {
+                total_bytes: compacted_size,
+                dead_bytes: compacted_size - live_bytes,
+            },
+        })
+    }
+
+    /// Return true if a file older than the given source survives the compaction.
+    fn shadows_older(&self, number: u64) -> bool {
+        self.oldest_surviving.is_some_and(|oldest| oldest < number)
+    }
+
+    /// Add the keys removed in those source files which shadow an older file, unless they
+    /// have been set again since.
+    fn find_removals(&self, keys: &mut HashSet<Vec<u8>>) -> Result<()> {
+        for &number in &self.sources {
+            if !self.shadows_older(number) {
+                continue;
+            }
+            let file_path = self.dir_path.join(format!("data_{}.txt", number));
+            let mut records = RecordReader::<Command>::open(&file_path, number, false)?;
+            while let Some((command, _)) = records.next()? {
+                if let Command::RM(key) = command {
+                    if !self.index.contains_key(&key) {
+                        keys.insert(key);
+                    }
+                }
+            }
+        }
         Ok(())
     }
 }
 
+/// An entry of a hint file: where the record of a key is in the data file next to it.
+#[derive(Serialize, Deserialize)]
+struct HintEntry {
+    #[serde(with = "bytes")]
+    key: Vec<u8>,
+    file_number: u64,
+    offset: u64,
+    length: u64,
+    expires_at: Option<u64>,
+    /// the record is a removal
+    #[serde(default)]
+    removed: bool,
+}
+
+/// Write the hint file `data_<N>.hint` of a compacted data file, listing the position of every
+/// record in it, so recovery can rebuild the index without reading the values.
+/// The hint is written to a temporary file which is synced and renamed once complete.
+fn write_hint(
+    file_path: &Path,
+    copies: &[(Vec<u8>, CommandPosition, CommandPosition)],
+    tombstones: &[(Vec<u8>, CommandPosition)],
+) -> Result<()> {
+    let temp_path = file_path.with_extension("hint.compact");
+    let mut writer = BufWriter::new(File::create(&temp_path)?);
+    let mut record = Vec::new();
+    for (key, _, copy) in copies {
+        record.clear();
+        let entry = HintEntry {
+            key: key.clone(),
+            file_number: copy.file_number,
+            offset: copy.offset,
+            length: copy.length,
+            expires_at: copy.expires_at,
+            removed: false,
+        };
+        encode_record(&entry, &mut record)?;
+        writer.write_all(&record)?;
+    }
+    for (key, tombstone) in tombstones {
+        record.clear();
+        let entry = HintEntry {
+            key: key.clone(),
+            file_number: tombstone.file_number,
+            offset: tombstone.offset,
+            length: tombstone.length,
+            expires_at: None,
+            removed: true,
+        };
+        encode_record(&entry, &mut record)?;
+        writer.write_all(&record)?;
+    }
+    writer.flush()?;
+    writer.get_ref().sync_data()?;
+    rename(&temp_path, file_path.with_extension("hint"))?;
+    Ok(())
+}
+
+/// Load the hint file of a data file. Return None if there is no hint, or if it is damaged or
+/// does not match the data file, in which case the data file has to be scanned.
+fn load_hint(file_path: &Path, file_number: u64) -> Result<Option<Vec<HintEntry>>> {
+    let hint_path = file_path.with_extension("hint");
+    if !hint_path.exists() {
+        return Ok(None);
+    }
+    let data_length = fs::metadata(file_path)?.len();
+    let mut records = RecordReader::<HintEntry>::open(&hint_path, file_number, false)?;
+    let mut entries = Vec::new();
+    loop {
+        let entry = match records.next() {
+            Ok(Some((entry, _))) => entry,
+            Ok(None) => return Ok(Some(entries)),
+            Err(KVStoreError::Corruption { .. }) => break,
+            Err(err) => return Err(err),
+        };
+        if entry.file_number != file_number || entry.offset + entry.length > data_length {
+            break;
+        }
+        entries.push(entry);
+    }
+    warn!("ignore damaged hint file {:?}", hint_path);
+    Ok(None)
+}
+
+/// Take an exclusive lock on the `LOCK` file of the store directory, which is released when
+/// the returned file is closed.
+fn lock_dir(dir_path: &Path) -> Result<File> {
+    let lock = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(dir_path.join("LOCK"))?;
+    match lock.try_lock_exclusive() {
+        Ok(()) => Ok(lock),
+        Err(err) if err.kind() == fs2::lock_contended_error().kind() => {
+            Err(KVStoreError::StoreLocked(dir_path.to_path_buf()))
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Return the numbers of the data files in a directory, in ascending order.
+fn data_file_numbers(dir_path: &Path) -> Result<Vec<u64>> {
+    let mut numbers: Vec<u64> = read_dir(dir_path)?
+        .flat_map(|res| res.map(|e| e.path()))
+        .filter(|path| path.is_file() && path.extension() == Some("txt".as_ref()))
+        .flat_map(|path| {
+            path.file_name()
+                .and_then(|filename| filename.to_str())
+                .map(|filename| {
+                    filename
+                        .trim_start_matches("data_")
+                        .trim_end_matches(".txt")
+                })
+                .map(str::parse::<u64>)
+        })
+        .flatten()
+        .collect();
+    numbers.sort();
+    Ok(numbers)
+}
+
+/// Read the live value of a key.
+fn read_value(index: &Index, reader: &Reader, key: &[u8]) -> Result<Option<Vec<u8>>> {
+    Ok(read_entry(index, reader, key)?.map(|(value, _)| value))
+}
+
+/// Read the live value of a key and its expiry. A compaction may delete the file an index
+/// entry pointed to right after the entry was looked up, in which case the lookup is repeated.
+fn read_entry(
+    index: &Index,
+    reader: &Reader,
+    key: &[u8],
+) -> Result<Option<(Vec<u8>, Option<u64>)>> {
+    loop {
+        let position = match index.get(key) {
+            Some(position) if !position.is_expired(now_millis()) => position,
+            _ => return Ok(None),
+        };
+        match reader.read_command(&position) {
+            Ok(value) => return Ok(value.map(|value| (value, position.expires_at))),
+            Err(KVStoreError::Io(err))
+                if err.kind() == io::ErrorKind::NotFound
+                    && index
+                        .get(key)
+                        .is_some_and(|current| !current.same_record(&position)) =>
+            {
+                continue
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Commits writes of all callers in groups on a single thread, so concurrent writers share
+/// one write and one sync per group instead of taking turns on the writer lock.
+/// The thread is stopped and joined when the last `KvStore` clone drops it.
+struct GroupCommitter {
+    sender: Option<Sender<(WriteOp, SyncSender<Result<()>>)>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl GroupCommitter {
+    fn spawn(writer: Arc<Mutex<Writer>>) -> Self {
+        let (sender, receiver) = mpsc::channel::<(WriteOp, SyncSender<Result<()>>)>();
+        let handle = thread::spawn(move || {
+            while let Ok(first) = receiver.recv() {
+                let mut writer = writer.lock().unwrap();
+                let mut waiting = Vec::new();
+                let mut next = Some(first);
+                while let Some((op, reply)) = next {
+                    match writer.stage(op) {
+                        Ok(()) => waiting.push(reply),
+                        Err(err) => {
+                            let _ = reply.send(Err(err));
+                        }
+                    }
+                    if waiting.len() >= MAX_GROUP_SIZE {
+                        break;
+                    }
+                    next = receiver.try_recv().ok();
+                }
+                let result = writer.commit();
+                drop(writer);
+                if let Err(err) = &result {
+                    error!("group commit failed: {}", err);
+                }
+                for reply in waiting {
+                    let _ = reply.send(match &result {
+                        Ok(()) => Ok(()),
+                        Err(err) => Err(KVStoreError::CommitFailed(err.to_string())),
+                    });
+                }
+            }
+        });
+        GroupCommitter {
+            sender: Some(sender),
+            handle: Some(handle),
+        }
+    }
+
+    /// Queue a write and wait until the group it is committed in is on disk.
+    fn write(&self, op: WriteOp) -> Result<()> {
+        let stopped = || KVStoreError::CommitFailed("log writer stopped".to_owned());
+        let (reply, receiver) = mpsc::sync_channel(1);
+        self.sender
+            .as_ref()
+            .expect("log writer is running until dropped")
+            .send((op, reply))
+            .map_err(|_| stopped())?;
+        receiver.recv().map_err(|_| stopped())?
+    }
+}
+
+impl Drop for GroupCommitter {
+    fn drop(&mut self) {
+        // disconnect the channel so the thread exits once the queued writes are committed
+        self.sender.take();
+        if let Some(handle) = self.handle.take() {
+            if handle.join().is_err() {
+                error!("log writer thread panicked");
+            }
+        }
+    }
+}
+
+/// Syncs the writer in the background for `SyncPolicy::Interval`.
+/// The thread is stopped and joined when the last `KvStore` clone drops it.
+struct IntervalSyncer {
+    sender: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl IntervalSyncer {
+    fn spawn(writer: Arc<Mutex<Writer>>, interval: Duration) -> Self {
+        let (sender, receiver) = mpsc::channel();
+        let handle = thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = receiver.recv_timeout(interval) {
+                if let Err(err) = writer.lock().unwrap().sync_pending() {
+                    error!("background sync failed: {}", err);
+                }
+            }
+        });
+        IntervalSyncer {
+            sender: Some(sender),
+            handle: Some(handle),
+        }
+    }
+}
+
+impl Drop for IntervalSyncer {
+    fn drop(&mut self) {
+        // disconnect the channel so the thread wakes up and exits
+        self.sender.take();
+        if let Some(handle) = self.handle.take() {
+            if handle.join().is_err() {
+                error!("background sync thread panicked");
+            }
+        }
+    }
+}
+
+/// Apply a logged command to the index, counting the records it made dead in `files`.
+fn apply_to_index(
+    index: &Index,
+    files: &mut BTreeMap<u64, FileStats>,
+    command: Command,
+    position: CommandPosition,
+) {
+    match command {
+        Command::SET(key, _, expires_at) => insert_to_index(
+            index,
+            files,
+            key,
+            CommandPosition {
+                expires_at,
+                ..position
+            },
+        ),
+        Command::RM(key) => {
+            if let Some(old) = index.remove(&key) {
+                add_dead(files, &old);
+            }
+            add_dead(files, &position);
+        }
+        Command::BATCH(_) => add_dead(files, &position),
+    }
+}
+
+/// Point a key to a new record, counting the record it pointed to before as dead.
+fn insert_to_index(
+    index: &Index,
+    files: &mut BTreeMap<u64, FileStats>,
+    key: Vec<u8>,
+    position: CommandPosition,
+) {
+    if let Some(old) = index.insert(key, position) {
+        add_dead(files, &old);
+    }
+}
+
+fn add_dead(files: &mut BTreeMap<u64, FileStats>, position: &CommandPosition) {
+    files.entry(position.file_number).or_default().dead_bytes += position.length;
+}
+
+fn add_total(files: &mut BTreeMap<u64, FileStats>, file_number: u64, length: u64) {
+    files.entry(file_number).or_default().total_bytes += length;
+}
+
+/// Append a command, or hint entry, to `buf` as a record: the big-endian length of the JSON
+/// payload, the big-endian CRC32 of the payload, then the payload itself.
+fn encode_record<T: Serialize>(value: &T, buf: &mut Vec<u8>) -> Result<()> {
+    let start = buf.len();
+    buf.extend_from_slice(&[0; RECORD_HEADER_SIZE as usize]);
+    serde_json::to_writer(&mut *buf, value)?;
+    let payload = &buf[start + RECORD_HEADER_SIZE as usize..];
+    let length = (payload.len() as u32).to_be_bytes();
+    let crc = crc32fast::hash(payload).to_be_bytes();
+    buf[start..start + 4].copy_from_slice(&length);
+    buf[start + 4..start + 8].copy_from_slice(&crc);
+    Ok(())
+}
+
+/// Return true if `record` is one whole record whose payload matches its checksum.
+fn check_record(record: &[u8]) -> bool {
+    if record.len() < RECORD_HEADER_SIZE as usize {
+        return false;
+    }
+    let (header, payload) = record.split_at(RECORD_HEADER_SIZE as usize);
+    let length = u32::from_be_bytes([header[0], header[1], header[2], header[3]]);
+    let crc = u32::from_be_bytes([header[4], header[5], header[6], header[7]]);
+    payload.len() == length as usize && crc32fast::hash(payload) == crc
+}
+
+/// Decode one whole record. Return None if it is damaged.
+fn decode_record<T: DeserializeOwned>(record: &[u8]) -> Option<T> {
+    if !check_record(record) {
+        return None;
+    }
+    serde_json::from_slice(&record[RECORD_HEADER_SIZE as usize..]).ok()
+}
+
+/// Reads the records of a data file in order during recovery.
+///
+/// A damaged record at the end of the active file, with no valid record after it, is what a
+/// crash in the middle of a write leaves behind, so it ends the file and its offset is kept in
+/// `torn_offset` for truncation. Any other damaged record is reported as
+/// `KVStoreError::Corruption`.
+struct RecordReader<T = Command> {
+    reader: BufReader<File>,
+    file_number: u64,
+    file_length: u64,
+    is_active: bool,
+    offset: u64,
+    torn_offset: Option<u64>,
+    record_type: PhantomData<T>,
+}
+
+impl<T: DeserializeOwned> RecordReader<T> {
+    fn open(path: &Path, file_number: u64, is_active: bool) -> Result<Self> {
+        let file = File::open(path)?;
+        let file_length = file.metadata()?.len();
+        Ok(RecordReader {
+            reader: BufReader::new(file),
+            file_number,
+            file_length,
+            is_active,
+            offset: 0,
+            torn_offset: None,
+            record_type: PhantomData,
+        })
+    }
+
+    /// Return the next record and its position, or None at the end of the file.
+    fn next(&mut self) -> Result<Option<(T, CommandPosition)>> {
+        if self.torn_offset.is_some() || self.offset == self.file_length {
+            return Ok(None);
+        }
+        let position = CommandPosition {
+            offset: self.offset,
+            length: 0,
+            file_number: self.file_number,
+            expires_at: None,
+        };
+        let remaining = self.file_length - self.offset;
+        if remaining < RECORD_HEADER_SIZE {
+            return self.damaged(position);
+        }
+        let mut header = [0; RECORD_HEADER_SIZE as usize];
+        self.reader.read_exact(&mut header)?;
+        let length = RECORD_HEADER_SIZE
+            + u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as u64;
+        if length > remaining {
+            return self.damaged(position);
+        }
+
+        let mut record = Vec::with_capacity(length as usize);
+        record.extend_from_slice(&header);
+        record.resize(length as usize, 0);
+        self.reader
+            .read_exact(&mut record[RECORD_HEADER_SIZE as usize..])?;
+        self.offset += length;
+        match decode_record(&record) {
+            Some(command) => Ok(Some((command, CommandPosition { length, ..position }))),
+            None => self.damaged(position),
+        }
+    }
+
+    fn damaged(&mut self, position: CommandPosition) -> Result<Option<(T, CommandPosition)>> {
+        // a damaged length makes a record look longer than the rest of the file wherever it is
+        if !self.is_active || self.valid_record_after(position.offset)? {
+            return Err(position.corruption());
+        }
+        warn!(
+            "drop torn record at the end of data_{}.txt, offset {}",
+            self.file_number, position.offset
+        );
+        self.torn_offset = Some(position.offset);
+        Ok(None)
+    }
+
+    /// Return true if a whole valid record starts anywhere after `offset`.
+    ///
+    /// Payloads are JSON, which escapes control characters, so the zero bytes a length starts
+    /// with never occur inside a payload and a record found here is not part of the one torn.
+    fn valid_record_after(&mut self, offset: u64) -> Result<bool> {
+        self.reader.seek(SeekFrom::Start(offset + 1))?;
+        let mut rest = Vec::new();
+        self.reader.read_to_end(&mut rest)?;
+        let header_size = RECORD_HEADER_SIZE as usize;
+        Ok((0..rest.len()).any(|start| {
+            let data = &rest[start..];
+            if data.len() < header_size {
+                return false;
+            }
+            let length =
+                header_size + u32::from_be_bytes([data[0], data[1], data[2], data[3]]) as usize;
+            length <= data.len() && decode_record::<T>(&data[..length]).is_some()
+        }))
+    }
+}
+
+/// Return true if the data file was written before records had a header: JSON commands one
+/// after the other. A record of the current format would need a length of almost 2 GiB to
+/// start with the same bytes.
+fn is_legacy_file(file_path: &Path) -> Result<bool> {
+    let mut start = Vec::with_capacity(2);
+    File::open(file_path)?.take(2).read_to_end(&mut start)?;
+    Ok(start == b"{\"")
+}
+
+/// Rewrite a data file of the legacy format in the current record format. The converted file
+/// is renamed over the old one once it is complete, so a crash leaves the old file to be
+/// converted again on the next open.
+///
+/// A command cut short at the end of the active file is dropped, like a torn record.
+fn upgrade_legacy_file(file_path: &Path, file_number: u64, is_active: bool) -> Result<()> {
+    info!("convert {:?} to the current record format", file_path);
+    let reader = BufReader::new(File::open(file_path)?);
+    let mut commands = serde_json::Deserializer::from_reader(reader).into_iter::<Command>();
+    let temp_path = file_path.with_extension("upgrade");
+    let mut writer = BufWriter::new(File::create(&temp_path)?);
+    let mut record = Vec::new();
+    loop {
+        let offset = commands.byte_offset() as u64;
+        match commands.next() {
+            Some(Ok(command)) => {
+                record.clear();
+                encode_record(&command, &mut record)?;
+                writer.write_all(&record)?;
+            }
+            Some(Err(err)) if is_active && err.is_eof() => {
+                warn!(
+                    "drop torn command at the end of {:?}, offset {}",
+                    file_path, offset
+                );
+                break;
+            }
+            Some(Err(_)) => {
+                return Err(KVStoreError::Corruption {
+                    file_number,
+                    offset,
+                })
+            }
+            None => break,
+        }
+    }
+    writer.flush()?;
+    writer.get_ref().sync_data()?;
+    rename(&temp_path, file_path)?;
+    Ok(())
+}
+
 /// a struct which records writer's current position
 struct BufWriterWithPosition<T: Write + Seek> {
     position: u64,
@@ -404,6 +1602,14 @@ impl<T: Write + Seek> BufWriterWithPosition<T> {
     }
 }
 
+impl BufWriterWithPosition<File> {
+    /// Flush the buffer and sync the file's data to disk.
+    fn sync_data(&mut self) -> io::Result<()> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_data()
+    }
+}
+
 impl<T: Write + Seek> Write for BufWriterWithPosition<T> {
     /// Writes a buffer to the underlying writer, updating the position accordingly.
     ///
@@ -435,8 +1641,29 @@ impl<T: Write + Seek> Write for BufWriterWithPosition<T> {
 }
 
 /// a struct which records command's metadata
+#[derive(Clone, Copy)]
 struct CommandPosition {
     offset: u64,
     length: u64,
     file_number: u64,
+    /// expiry of a `SET` in milliseconds since the unix epoch
+    expires_at: Option<u64>,
+}
+
+impl CommandPosition {
+    /// Return true if both positions point to the same record.
+    fn same_record(&self, other: &CommandPosition) -> bool {
+        self.file_number == other.file_number && self.offset == other.offset
+    }
+
+    fn corruption(&self) -> KVStoreError {
+        KVStoreError::Corruption {
+            file_number: self.file_number,
+            offset: self.offset,
+        }
+    }
+
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
 }
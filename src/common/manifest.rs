@@ -0,0 +1,117 @@
+use crate::{EngineType, KVStoreError, Result};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
+
+/// name of the manifest file in a store directory
+const MANIFEST_FILE: &str = "MANIFEST";
+/// format version of data written before stores had a manifest
+pub(crate) const LEGACY_FORMAT_VERSION: u32 = 0;
+
+/** The metadata a store keeps next to its data: which engine wrote it, the version of its
+on-disk format, and the options it was created with.
+
+The manifest is written when a store is created, and checked whenever it is opened.
+# Example
+```
+use std::env;
+use blaze_turbo::{EngineType, KvStore, Manifest, Result};
+# fn try_main() -> Result<()> {
+
+let store = KvStore::open(env::current_dir()?)?;
+let manifest = Manifest::load(env::current_dir()?)?.unwrap();
+assert_eq!(manifest.engine, EngineType::KvStore);
+# Ok(())
+# }
+```
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Manifest {
+    /// the engine which created the store
+    pub engine: EngineType,
+    /// version of the on-disk format
+    pub format_version: u32,
+    /// the options the store was created with
+    pub options: serde_json::Value,
+    /// the engine the data was migrated to, after which the store is no longer opened
+    #[serde(default)]
+    pub migrated_to: Option<EngineType>,
+}
+
+impl Manifest {
+    /// Read the manifest of the store in `dir`. Return None if there is none.
+    pub fn load(dir: impl AsRef<Path>) -> Result<Option<Manifest>> {
+        match fs::read(dir.as_ref().join(MANIFEST_FILE)) {
+            Ok(data) => Ok(Some(serde_json::from_slice(&data)?)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Replace the manifest of the store in `dir` with this one. The new manifest is written
+    /// to a temporary file first, so a crash leaves either the old or the new one.
+    pub fn store(&self, dir: impl AsRef<Path>) -> Result<()> {
+        let path = dir.as_ref().join(MANIFEST_FILE);
+        let temp_path = path.with_extension("tmp");
+        let mut file = File::create(&temp_path)?;
+        serde_json::to_writer_pretty(&mut file, self)?;
+        file.write_all(b"\n")?;
+        file.sync_all()?;
+        fs::rename(&temp_path, &path)?;
+        Ok(())
+    }
+
+    /// Check the manifest of the store in `dir` against the engine opening it, or write one if
+    /// the store has none yet. Return the manifest.
+    ///
+    /// A store without a manifest which `has_data` was written before manifests existed, so its
+    /// manifest records `LEGACY_FORMAT_VERSION` until the engine has upgraded the data.
+    pub(crate) fn open(
+        dir: &Path,
+        engine: EngineType,
+        format_version: u32,
+        options: impl Serialize,
+        has_data: bool,
+    ) -> Result<Manifest> {
+        match Manifest::load(dir)? {
+            Some(manifest) => {
+                if manifest.engine != engine {
+                    return Err(KVStoreError::ChangeEngineError);
+                }
+                if let Some(migrated_to) = manifest.migrated_to {
+                    return Err(KVStoreError::Migrated(migrated_to));
+                }
+                if manifest.format_version > format_version {
+                    return Err(KVStoreError::UnsupportedFormatVersion(
+                        manifest.format_version,
+                    ));
+                }
+                Ok(manifest)
+            }
+            None => {
+                let manifest = Manifest {
+                    engine,
+                    format_version: if has_data {
+                        LEGACY_FORMAT_VERSION
+                    } else {
+                        format_version
+                    },
+                    options: serde_json::to_value(options)?,
+                    migrated_to: None,
+                };
+                manifest.store(dir)?;
+                Ok(manifest)
+            }
+        }
+    }
+
+    /// Record that the data of the store in `dir` is now in the given format version.
+    pub(crate) fn upgrade(&mut self, dir: &Path, format_version: u32) -> Result<()> {
+        if self.format_version < format_version {
+            self.format_version = format_version;
+            self.store(dir)?;
+        }
+        Ok(())
+    }
+}
@@ -1,31 +1,256 @@
 pub mod error;
 use crate::Result;
 use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+pub(crate) mod encoding;
 mod kv;
+mod manifest;
+mod options;
 mod sled;
 
-pub use self::kv::KvStore;
+use self::encoding::bytes;
+pub use self::kv::{FileStats, KvStore};
+pub use self::manifest::Manifest;
+use self::manifest::LEGACY_FORMAT_VERSION;
+pub use self::options::{KvStoreOptions, SledKvsEngineOptions, SyncPolicy};
 pub use self::sled::SledKvsEngine;
 
-/// A trait which supports pluggable storage engines
+/// Key/value pairs returned by scans, in key order.
+pub type KvPairs = Vec<(Vec<u8>, Vec<u8>)>;
+
+/// Key/value pairs with the time left before each key expires, None for keys set without a
+/// ttl, in key order.
+pub type KvEntries = Vec<(Vec<u8>, Vec<u8>, Option<Duration>)>;
+
+/// A trait which supports pluggable storage engines.
+///
+/// Keys and values are arbitrary bytes. The string methods are layered on top of the byte
+/// methods and store the UTF-8 encoding of their arguments.
 pub trait KvsEngine: Clone + Send + 'static {
+    /// Set the value of a key to the given bytes.
+    /// Return an error if the value is not written successfully.
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()>;
+    /// Set the value of a key which expires after `ttl`.
+    /// Once expired the key behaves as if it had been removed.
+    fn set_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()>;
+    /// Get the value of a key. If the key does not exist, return None.
+    /// Return an error if the value is not read successfully.
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>>;
+    /// Remove a given key.
+    /// Return an error if the key does not exit or value is not read successfully.
+    fn remove_bytes(&self, key: &[u8]) -> Result<()>;
+    /// Apply every write of the batch, or none of them if an error occurs.
+    /// Removing a key which does not exist is not an error inside a batch.
+    fn write_batch(&self, batch: WriteBatch) -> Result<()>;
+    /// Set the value of a key to `new`, or remove the key if `new` is None, but only if its
+    /// current value equals `expected` (None meaning the key does not exist).
+    /// Otherwise return `KVStoreError::CompareAndSwapFailed` holding the current value.
+    fn compare_and_swap(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<()>;
+    /// Like `scan`, but also return the time left before each key expires, so the keys can be
+    /// copied along with their ttl.
+    fn scan_with_ttl(
+        &self,
+        start: &[u8],
+        end: Option<&[u8]>,
+        limit: Option<usize>,
+    ) -> Result<KvEntries>;
+    /// Reclaim the space of overwritten and removed entries now.
+    /// Return once it is done, or an error if it fails.
+    fn compact(&self) -> Result<()>;
+    /// Force every acknowledged write to disk, whatever the sync policy.
+    fn flush(&self) -> Result<()>;
+    /// Write a consistent copy of the store to the new or empty directory `dest`, which can be
+    /// opened as a store of its own. Writes continue during and after the snapshot.
+    fn snapshot(&self, dest: &Path) -> Result<()>;
+
+    /// Return up to `limit` key/value pairs with `start <= key < end`, in key order.
+    /// Without `end` the scan runs to the last key, without `limit` every match is returned.
+    fn scan(&self, start: &[u8], end: Option<&[u8]>, limit: Option<usize>) -> Result<KvPairs> {
+        Ok(self
+            .scan_with_ttl(start, end, limit)?
+            .into_iter()
+            .map(|(key, value, _)| (key, value))
+            .collect())
+    }
+
+    /// Return every key/value pair whose key starts with `prefix`, in key order.
+    fn scan_prefix(&self, prefix: &[u8]) -> Result<KvPairs> {
+        self.scan(prefix, prefix_end(prefix).as_deref(), None)
+    }
+
+    /// Set the value of a key only if the key does not exist yet.
+    /// Otherwise return `KVStoreError::CompareAndSwapFailed` holding the current value.
+    fn set_if_absent(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.compare_and_swap(key, None, Some(value))
+    }
+
     /// Set the value of a string key to a string.
     /// Return an error if the value is not written successfully.
-    fn set(&self, key: String, value: String) -> Result<()>;
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
     /// Get the string value of a string key. If the key does not exist, return None.
-    /// Return an error if the value is not read successfully.
-    fn get(&self, key: String) -> Result<Option<String>>;
+    /// Return an error if the value is not read successfully or is not valid UTF-8.
+    fn get(&self, key: String) -> Result<Option<String>> {
+        Ok(self
+            .get_bytes(key.as_bytes())?
+            .map(String::from_utf8)
+            .transpose()?)
+    }
     /// Remove a given string key.
     /// Return an error if the key does not exit or value is not read successfully.
-    fn remove(&self, key: String) -> Result<()>;
+    fn remove(&self, key: String) -> Result<()> {
+        self.remove_bytes(key.as_bytes())
+    }
+}
+
+/// Return the current time in milliseconds since the unix epoch.
+pub(crate) fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_millis() as u64)
+}
+
+/// Return the expiry, in milliseconds since the unix epoch, of a key set now with `ttl`.
+pub(crate) fn expires_at(ttl: Duration) -> u64 {
+    now_millis().saturating_add(ttl.as_millis() as u64)
+}
+
+/// Create the directory a snapshot is written to. Return an error if it holds any file.
+pub(crate) fn create_snapshot_dir(dest: &Path) -> Result<()> {
+    fs::create_dir_all(dest)?;
+    if fs::read_dir(dest)?.next().is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("snapshot directory {:?} is not empty", dest),
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// Return the smallest key which is greater than every key starting with `prefix`,
+/// or None if there is no such key (the prefix is empty or all `0xff`).
+pub(crate) fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return Some(end);
+        }
+    }
+    None
 }
 
 /// a struct which supports serialization and deserialization
 #[derive(Serialize, Deserialize, Debug)]
 pub enum Command {
-    /// for set command
-    SET(String, String),
+    /// for set command, with an optional expiry in milliseconds since the unix epoch
+    SET(
+        #[serde(with = "bytes")] Vec<u8>,
+        #[serde(with = "bytes")] Vec<u8>,
+        #[serde(default)] Option<u64>,
+    ),
     /// for rm command
-    RM(String),
+    RM(#[serde(with = "bytes")] Vec<u8>),
+    /// header of a write batch, followed by the given number of commands
+    BATCH(usize),
+}
+
+/** A group of writes which `KvsEngine::write_batch` applies atomically.
+# Example
+```
+use std::env;
+use blaze_turbo::{KvStore, KvsEngine, Result, WriteBatch};
+# fn try_main() -> Result<()> {
+
+let store = KvStore::open(env::current_dir()?)?;
+
+let mut batch = WriteBatch::new();
+batch.set(b"1".to_vec(), b"1".to_vec());
+batch.remove(b"2".to_vec());
+store.write_batch(batch)?;
+assert_eq!(store.get("1".to_owned())?, Some("1".to_owned()));
+# Ok(())
+# }
+```
+ */
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(transparent)]
+pub struct WriteBatch {
+    ops: Vec<BatchOp>,
+}
+
+/// a write of a `WriteBatch`, encoded like the `Command` it becomes. Unlike a `Command` it
+/// cannot be a batch header, so a batch received from a client cannot nest one.
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) enum BatchOp {
+    /// set a key, with an optional expiry in milliseconds since the unix epoch
+    #[serde(rename = "SET")]
+    Set(
+        #[serde(with = "bytes")] Vec<u8>,
+        #[serde(with = "bytes")] Vec<u8>,
+        #[serde(default)] Option<u64>,
+    ),
+    /// remove a key
+    #[serde(rename = "RM")]
+    Rm(#[serde(with = "bytes")] Vec<u8>),
+}
+
+impl From<BatchOp> for Command {
+    fn from(op: BatchOp) -> Command {
+        match op {
+            BatchOp::Set(key, value, expires_at) => Command::SET(key, value, expires_at),
+            BatchOp::Rm(key) => Command::RM(key),
+        }
+    }
+}
+
+impl WriteBatch {
+    /// Create an empty batch.
+    pub fn new() -> Self {
+        WriteBatch::default()
+    }
+
+    /// Queue setting the value of a key.
+    pub fn set(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        self.ops.push(BatchOp::Set(key, value, None));
+    }
+
+    /// Queue setting the value of a key which expires after `ttl`, counted from now.
+    pub fn set_with_ttl(&mut self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) {
+        self.ops
+            .push(BatchOp::Set(key, value, Some(expires_at(ttl))));
+    }
+
+    /// Queue removing a key.
+    pub fn remove(&mut self, key: Vec<u8>) {
+        self.ops.push(BatchOp::Rm(key));
+    }
+
+    /// Return the number of queued writes.
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    /// Return true if no write is queued.
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    /// Return the key of every write of the batch, which are all sets and removals.
+    pub(crate) fn keys(&self) -> impl Iterator<Item = &[u8]> {
+        self.ops.iter().map(|op| match op {
+            BatchOp::Set(key, _, _) | BatchOp::Rm(key) => &key[..],
+        })
+    }
 }
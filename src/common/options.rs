@@ -0,0 +1,170 @@
+use crate::KVStoreError;
+use serde::{Serialize, Serializer};
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// When an engine forces acknowledged writes from the OS page cache to disk.
+///
+/// The textual form accepted by `FromStr` and produced by `Display` is one of `never`,
+/// `every-write`, `every-n:<WRITES>` and `interval:<MILLISECONDS>`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum SyncPolicy {
+    /// never sync explicitly, writes reach the disk whenever the OS (or sled) flushes them
+    #[default]
+    Never,
+    /// sync before acknowledging every write
+    EveryWrite,
+    /// sync once every given number of writes
+    EveryN(u64),
+    /// sync pending writes in the background at the given interval
+    Interval(Duration),
+}
+
+impl FromStr for SyncPolicy {
+    type Err = KVStoreError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let unknown = || KVStoreError::UnknownSyncPolicy(s.to_owned());
+        match s.split_once(':') {
+            None if s == "never" => Ok(SyncPolicy::Never),
+            None if s == "every-write" => Ok(SyncPolicy::EveryWrite),
+            Some(("every-n", n)) => match n.parse() {
+                Ok(n) if n > 0 => Ok(SyncPolicy::EveryN(n)),
+                _ => Err(unknown()),
+            },
+            Some(("interval", millis)) => match millis.parse() {
+                Ok(millis) if millis > 0 => Ok(SyncPolicy::Interval(Duration::from_millis(millis))),
+                _ => Err(unknown()),
+            },
+            _ => Err(unknown()),
+        }
+    }
+}
+
+/// Serialized in its textual form, as recorded in the manifest.
+impl Serialize for SyncPolicy {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl fmt::Display for SyncPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SyncPolicy::Never => write!(f, "never"),
+            SyncPolicy::EveryWrite => write!(f, "every-write"),
+            SyncPolicy::EveryN(n) => write!(f, "every-n:{}", n),
+            SyncPolicy::Interval(interval) => write!(f, "interval:{}", interval.as_millis()),
+        }
+    }
+}
+
+/** Options for opening a `KvStore`.
+# Example
+```
+use std::env;
+use blaze_turbo::{KvStore, KvStoreOptions, Result, SyncPolicy};
+# fn try_main() -> Result<()> {
+
+let options = KvStoreOptions::new().sync_policy(SyncPolicy::EveryWrite);
+let store = KvStore::open_with(env::current_dir()?, options)?;
+# Ok(())
+# }
+```
+ */
+#[derive(Clone, Debug, Serialize)]
+pub struct KvStoreOptions {
+    pub(crate) sync_policy: SyncPolicy,
+    pub(crate) group_commit: bool,
+    pub(crate) compaction_threshold: u64,
+    pub(crate) garbage_ratio: f64,
+    pub(crate) max_file_size: u64,
+    pub(crate) merge_ratio: f64,
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> Self {
+        KvStoreOptions {
+            sync_policy: SyncPolicy::default(),
+            group_commit: true,
+            compaction_threshold: 1024 * 1024,
+            garbage_ratio: 0.0,
+            max_file_size: 64 * 1024 * 1024,
+            merge_ratio: 0.0,
+        }
+    }
+}
+
+impl KvStoreOptions {
+    /// Create the default options.
+    pub fn new() -> Self {
+        KvStoreOptions::default()
+    }
+
+    /// Commit concurrent writes in groups on a dedicated log writer thread, with one write
+    /// and one sync per group; the sync policy then counts groups instead of writes.
+    /// Otherwise every write is committed on its own under the writer lock.
+    /// The default is `true`.
+    pub fn group_commit(mut self, group_commit: bool) -> Self {
+        self.group_commit = group_commit;
+        self
+    }
+
+    /// Compact only once more than this many bytes of the data files are garbage, that is
+    /// overwritten or removed entries. The default is 1 MiB.
+    pub fn compaction_threshold(mut self, bytes: u64) -> Self {
+        self.compaction_threshold = bytes;
+        self
+    }
+
+    /// Compact only once at least this share, between 0.0 and 1.0, of the data files is
+    /// garbage. Both this and the compaction threshold must be reached. The default is 0.0.
+    pub fn garbage_ratio(mut self, ratio: f64) -> Self {
+        self.garbage_ratio = ratio;
+        self
+    }
+
+    /// Seal the active data file and start a new one once it grows to this many bytes.
+    /// The default is 64 MiB.
+    pub fn max_file_size(mut self, bytes: u64) -> Self {
+        self.max_file_size = bytes;
+        self
+    }
+
+    /// Let automatic compactions merge only the data files in which dead records make up at
+    /// least this ratio of the file, leaving mostly live files alone. The default of 0.0
+    /// merges every file.
+    pub fn merge_ratio(mut self, ratio: f64) -> Self {
+        self.merge_ratio = ratio;
+        self
+    }
+
+    /// Set when writes are synced to disk. The default is `SyncPolicy::Never`.
+    pub fn sync_policy(mut self, sync_policy: SyncPolicy) -> Self {
+        self.sync_policy = sync_policy;
+        self
+    }
+}
+
+/// Options for opening a `SledKvsEngine`.
+///
+/// `SyncPolicy::Interval` is handed to sled's own background flusher, `SyncPolicy::Never`
+/// keeps sled's default flushing.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct SledKvsEngineOptions {
+    pub(crate) sync_policy: SyncPolicy,
+}
+
+impl SledKvsEngineOptions {
+    /// Create the default options.
+    pub fn new() -> Self {
+        SledKvsEngineOptions::default()
+    }
+
+    /// Set when writes are flushed to disk. The default is `SyncPolicy::Never`.
+    pub fn sync_policy(mut self, sync_policy: SyncPolicy) -> Self {
+        self.sync_policy = sync_policy;
+        self
+    }
+}
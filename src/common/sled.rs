@@ -1,6 +1,18 @@
-use crate::{KVStoreError, KvsEngine, Result};
-use sled::Db;
-use std::path::PathBuf;
+use crate::common::{create_snapshot_dir, expires_at, now_millis, BatchOp, Manifest};
+use crate::{EngineType, SledKvsEngineOptions, SyncPolicy};
+use crate::{KVStoreError, KvEntries, KvsEngine, Result, WriteBatch};
+use sled::transaction::{
+    ConflictableTransactionError, ConflictableTransactionResult, TransactionalTree,
+};
+use sled::{Db, IVec, Transactional, Tree};
+use std::fs::create_dir_all;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+/// version of the on-disk format recorded in the manifest
+const FORMAT_VERSION: u32 = 1;
 
 /** A KvStore stores key/value pairs using sled.
 # Example
@@ -23,39 +35,259 @@ assert_eq!(store.get("1".to_owned())?, None);
 #[derive(Clone)]
 pub struct SledKvsEngine {
     inner: Db,
+    /// expiry of keys set with a ttl, in big-endian milliseconds since the unix epoch
+    expiry: Tree,
+    sync_policy: SyncPolicy,
+    writes: Arc<AtomicU64>,
+    /// held shared by every write and exclusively by a snapshot, so the trees are copied
+    /// as of one moment
+    write_lock: Arc<RwLock<()>>,
 }
 
 impl SledKvsEngine {
     /// Open the SledKvsEngine at a given path. Return the SledKvsEngine.
     pub fn open(path: impl Into<PathBuf>) -> Result<SledKvsEngine> {
+        Self::open_with(path, SledKvsEngineOptions::default())
+    }
+
+    /// Open the SledKvsEngine at a given path with the given options. Return the SledKvsEngine.
+    pub fn open_with(
+        path: impl Into<PathBuf>,
+        options: SledKvsEngineOptions,
+    ) -> Result<SledKvsEngine> {
+        let path = path.into();
+        // checked before sled writes anything into the directory
+        create_dir_all(&path)?;
+        let has_data = path.join("db").exists();
+        let mut manifest = Manifest::open(
+            &path,
+            EngineType::SledKvsEngine,
+            FORMAT_VERSION,
+            &options,
+            has_data,
+        )?;
+        let mut config = sled::Config::new().path(&path);
+        if let SyncPolicy::Interval(interval) = options.sync_policy {
+            config = config.flush_every_ms(Some(interval.as_millis() as u64));
+        }
+        let inner = config.open()?;
+        let expiry = inner.open_tree("expiry")?;
+        // sled stores are laid out as they were before they had a manifest
+        manifest.upgrade(&path, FORMAT_VERSION)?;
         Ok(SledKvsEngine {
-            inner: sled::open(path.into())?,
+            inner,
+            expiry,
+            sync_policy: options.sync_policy,
+            writes: Arc::new(AtomicU64::new(0)),
+            write_lock: Arc::new(RwLock::new(())),
         })
     }
-}
 
-impl KvsEngine for SledKvsEngine {
-    /// Set the value of a string key to a string. Return an error if the value is not written successfully.
-    fn set(&self, key: String, value: String) -> Result<()> {
-        self.inner.insert(key, value.into_bytes())?;
-        // self.inner.flush()?;
+    /// Flush a finished write to disk if the sync policy asks for it.
+    fn sync(&self) -> Result<()> {
+        let writes = self.writes.fetch_add(1, Ordering::SeqCst) + 1;
+        match self.sync_policy {
+            SyncPolicy::EveryWrite => self.inner.flush()?,
+            SyncPolicy::EveryN(n) if writes.is_multiple_of(n) => self.inner.flush()?,
+            _ => 0,
+        };
         Ok(())
     }
 
-    /// Get the string value of a string key. If the key does not exist, return None. Return an error if the value is not read successfully.
-    fn get(&self, key: String) -> Result<Option<String>> {
+    fn is_expired(&self, key: &[u8], now: u64) -> Result<bool> {
         Ok(self
-            .inner
+            .expiry
             .get(key)?
-            .map(|ivec| ivec.to_vec())
-            .map(String::from_utf8)
-            .transpose()?)
+            .is_some_and(|expires_at| decode_expiry(&expires_at) <= now))
     }
+}
 
-    /// Remove a given key. Return an error if the key does not exist or is not removed successfully.
-    fn remove(&self, key: String) -> Result<()> {
-        self.inner.remove(key)?.ok_or(KVStoreError::KeyNotFound)?;
+impl KvsEngine for SledKvsEngine {
+    /// Set the value of a key. Return an error if the value is not written successfully.
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        let _writing = self.write_lock.read().unwrap();
+        (&*self.inner, &self.expiry).transaction(|(data, expiry)| {
+            data.insert(key.as_slice(), value.as_slice())?;
+            expiry.remove(key.as_slice())?;
+            Ok(())
+        })?;
+        self.sync()
+    }
+
+    /// Set the value of a key and its expiry in one transaction.
+    fn set_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        let expires_at = expires_at(ttl).to_be_bytes();
+        let _writing = self.write_lock.read().unwrap();
+        (&*self.inner, &self.expiry).transaction(|(data, expiry)| {
+            data.insert(key.as_slice(), value.as_slice())?;
+            expiry.insert(key.as_slice(), &expires_at)?;
+            Ok(())
+        })?;
+        self.sync()
+    }
+
+    /// Get the value of a key. If the key does not exist or has expired, return None. Return an error if the value is not read successfully.
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self.inner.get(key)? {
+            Some(value) if !self.is_expired(key, now_millis())? => Ok(Some(value.to_vec())),
+            _ => Ok(None),
+        }
+    }
+
+    /// Apply the batch atomically in one transaction.
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        let _writing = self.write_lock.read().unwrap();
+        (&*self.inner, &self.expiry).transaction(|(data, expiry)| {
+            for op in &batch.ops {
+                match op {
+                    BatchOp::Set(key, value, None) => {
+                        data.insert(key.as_slice(), value.as_slice())?;
+                        expiry.remove(key.as_slice())?;
+                    }
+                    BatchOp::Set(key, value, Some(expires_at)) => {
+                        data.insert(key.as_slice(), value.as_slice())?;
+                        expiry.insert(key.as_slice(), &expires_at.to_be_bytes())?;
+                    }
+                    BatchOp::Rm(key) => {
+                        data.remove(key.as_slice())?;
+                        expiry.remove(key.as_slice())?;
+                    }
+                }
+            }
+            Ok(())
+        })?;
+        self.sync()
+    }
+
+    /// Compare and swap in one transaction, treating expired keys as absent.
+    fn compare_and_swap(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<()> {
+        let now = now_millis();
+        let _writing = self.write_lock.read().unwrap();
+        (&*self.inner, &self.expiry).transaction(|(data, expiry)| {
+            let current = live_value(data, expiry, &key, now)?;
+            if current.as_deref() != expected.as_deref() {
+                return Err(ConflictableTransactionError::Abort(
+                    KVStoreError::CompareAndSwapFailed(current.map(|ivec| ivec.to_vec())),
+                ));
+            }
+            match &new {
+                Some(value) => data.insert(key.as_slice(), value.as_slice())?,
+                None => data.remove(key.as_slice())?,
+            };
+            expiry.remove(key.as_slice())?;
+            Ok(())
+        })?;
+        self.sync()
+    }
+
+    /// Return key/value pairs in `[start, end)` in key order using sled's ordered iteration,
+    /// with the ttl left of each.
+    fn scan_with_ttl(
+        &self,
+        start: &[u8],
+        end: Option<&[u8]>,
+        limit: Option<usize>,
+    ) -> Result<KvEntries> {
+        let iter = match end {
+            Some(end) if end <= start => return Ok(Vec::new()),
+            Some(end) => self.inner.range(start..end),
+            None => self.inner.range(start..),
+        };
+        let now = now_millis();
+        let mut pairs = Vec::new();
+        for pair in iter {
+            if pairs.len() >= limit.unwrap_or(usize::MAX) {
+                break;
+            }
+            let (key, value) = pair?;
+            let expires_at = self
+                .expiry
+                .get(&key)?
+                .map(|expires_at| decode_expiry(&expires_at));
+            match expires_at {
+                Some(expires_at) if expires_at <= now => {}
+                expires_at => {
+                    let ttl = expires_at.map(|expires_at| Duration::from_millis(expires_at - now));
+                    pairs.push((key.to_vec(), value.to_vec(), ttl));
+                }
+            }
+        }
+        Ok(pairs)
+    }
+
+    /// Sled reclaims space on its own, so only flush it to disk.
+    fn compact(&self) -> Result<()> {
         self.inner.flush()?;
         Ok(())
     }
+
+    /// Flush sled to disk.
+    fn flush(&self) -> Result<()> {
+        self.inner.flush()?;
+        Ok(())
+    }
+
+    /// Copy every tree into a new sled database in `dest`, blocking writes while the trees are
+    /// copied so they are all copied as of the same moment.
+    fn snapshot(&self, dest: &Path) -> Result<()> {
+        create_snapshot_dir(dest)?;
+        let options = SledKvsEngineOptions::new().sync_policy(self.sync_policy);
+        Manifest::open(
+            dest,
+            EngineType::SledKvsEngine,
+            FORMAT_VERSION,
+            &options,
+            false,
+        )?;
+        let snapshot = sled::Config::new().path(dest).open()?;
+        {
+            // the export reads the trees lazily while they are imported
+            let _blocked = self.write_lock.write().unwrap();
+            snapshot.import(self.inner.export());
+        }
+        snapshot.flush()?;
+        Ok(())
+    }
+
+    /// Remove a given key. Return an error if the key does not exist or is not removed successfully.
+    fn remove_bytes(&self, key: &[u8]) -> Result<()> {
+        let now = now_millis();
+        let _writing = self.write_lock.read().unwrap();
+        (&*self.inner, &self.expiry).transaction(|(data, expiry)| {
+            if live_value(data, expiry, key, now)?.is_none() {
+                return Err(ConflictableTransactionError::Abort(
+                    KVStoreError::KeyNotFound,
+                ));
+            }
+            data.remove(key)?;
+            expiry.remove(key)?;
+            Ok(())
+        })?;
+        self.sync()
+    }
+}
+
+/// Return the value of a key inside a transaction, or None if it does not exist or has expired.
+fn live_value(
+    data: &TransactionalTree,
+    expiry: &TransactionalTree,
+    key: &[u8],
+    now: u64,
+) -> ConflictableTransactionResult<Option<IVec>, KVStoreError> {
+    let value = data.get(key)?;
+    match expiry.get(key)? {
+        Some(expires_at) if decode_expiry(&expires_at) <= now => Ok(None),
+        _ => Ok(value),
+    }
+}
+
+fn decode_expiry(bytes: &[u8]) -> u64 {
+    let mut expires_at = [0; 8];
+    expires_at.copy_from_slice(&bytes[..8]);
+    u64::from_be_bytes(expires_at)
 }
@@ -2,16 +2,30 @@
 /*!
 The KvStore store key/value pairs.
  */
+#[cfg(feature = "async")]
+mod async_client;
+#[cfg(feature = "async")]
+mod async_server;
+mod auth;
 mod client;
 mod common;
 mod proto;
 mod server;
 mod thread_pool;
+mod tls;
+mod transport;
 
-pub use client::Client;
+#[cfg(feature = "async")]
+pub use async_client::AsyncClient;
+#[cfg(feature = "async")]
+pub use async_server::AsyncKvServer;
+pub use auth::{hash_password, read_password, AccessControl, Permissions, User, PASSWORD_ENV};
+pub use client::{Client, Pipeline};
 pub use common::error::{KVStoreError, Result};
-pub use common::Command;
-pub use common::{KvStore, KvsEngine, SledKvsEngine};
-pub use proto::{Request, Response};
-pub use server::{EngineType, KvServer};
+pub use common::{Command, WriteBatch};
+pub use common::{FileStats, KvEntries, KvPairs, KvStore, KvsEngine, SledKvsEngine};
+pub use common::{KvStoreOptions, Manifest, SledKvsEngineOptions, SyncPolicy};
+pub use proto::{Protocol, Request, Response};
+pub use server::{EngineType, KvServer, ShutdownHandle};
 pub use thread_pool::{NaiveThreadPool, RayonThreadPool, SharedQueueThreadPool, ThreadPool};
+pub use tls::{ClientTlsConfig, ServerTlsConfig};
@@ -1,21 +1,426 @@
+use crate::common::encoding::{bytes, option_bytes, pairs};
+use crate::{KVStoreError, KvPairs, Result, WriteBatch};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use serde_json::Deserializer;
+use std::io::{self, BufRead, BufReader, Read, Write};
+#[cfg(feature = "async")]
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// first byte sent by a client which wants to speak the binary protocol
+const BINARY_MAGIC: u8 = 0xB7;
+/// version of the binary protocol spoken by this build
+const BINARY_VERSION: u8 = 1;
+/// frames larger than this are rejected instead of being allocated
+const MAX_FRAME_SIZE: usize = 64 * 1024 * 1024;
 
 /// a request struct which supports serialization and deserialization
 #[derive(Serialize, Deserialize, Debug)]
 pub enum Request {
     /// for set command
-    SET(String, String),
+    SET(
+        #[serde(with = "bytes")] Vec<u8>,
+        #[serde(with = "bytes")] Vec<u8>,
+    ),
+    /// for set command with a ttl in milliseconds
+    SETEX(
+        #[serde(with = "bytes")] Vec<u8>,
+        #[serde(with = "bytes")] Vec<u8>,
+        u64,
+    ),
     /// for rm command
-    RM(String),
+    RM(#[serde(with = "bytes")] Vec<u8>),
     /// for get command
-    GET(String),
+    GET(#[serde(with = "bytes")] Vec<u8>),
+    /// for cas command: key, expected value and new value, None meaning absent
+    CAS(
+        #[serde(with = "bytes")] Vec<u8>,
+        #[serde(with = "option_bytes")] Option<Vec<u8>>,
+        #[serde(with = "option_bytes")] Option<Vec<u8>>,
+    ),
+    /// for batch command
+    BATCH(WriteBatch),
+    /// for scan command: start key, optional end key (exclusive) and optional limit
+    SCAN(
+        #[serde(with = "bytes")] Vec<u8>,
+        #[serde(with = "option_bytes")] Option<Vec<u8>>,
+        Option<usize>,
+    ),
+    /// for compact command
+    COMPACT,
+    /// for backup command: the directory, on the server, to write a snapshot of the store to
+    BACKUP(String),
+    /// authenticate the connection: username and password
+    AUTH(String, String),
 }
 
 /// a response struct which supports serialization and deserialization
 #[derive(Serialize, Deserialize, Debug)]
 pub enum Response {
     /// for successful request
-    Ok(Option<String>),
+    Ok(#[serde(with = "option_bytes")] Option<Vec<u8>>),
+    /// for successful scan request
+    Entries(#[serde(with = "pairs")] KvPairs),
+    /// for cas request whose expected value did not match, holding the current value
+    CasFailed(#[serde(with = "option_bytes")] Option<Vec<u8>>),
     /// for failed request
     Err(String),
+    /// for request the connection is not allowed to make, holding the reason
+    PermissionDenied(String),
+}
+
+/// The wire format used on a connection.
+///
+/// `Json` sends bare concatenated JSON documents. `Binary` is negotiated by the client sending
+/// a magic byte and a version byte, which the server echoes back; after that every message
+/// is a big-endian `u32` length followed by a bincode payload.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Protocol {
+    /// concatenated JSON documents, no handshake
+    Json,
+    /// length-prefixed bincode frames
+    Binary,
+}
+
+/// Append a message to `output` in the given protocol.
+fn encode_message<T: Serialize>(
+    protocol: Protocol,
+    message: &T,
+    output: &mut Vec<u8>,
+) -> Result<()> {
+    match protocol {
+        Protocol::Json => serde_json::to_writer(output, message)?,
+        Protocol::Binary => {
+            let payload = bincode::serialize(message)?;
+            if payload.len() > MAX_FRAME_SIZE {
+                return Err(KVStoreError::FrameTooLarge(payload.len()));
+            }
+            output.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+            output.extend_from_slice(&payload);
+        }
+    }
+    Ok(())
+}
+
+/// a connection which reads and writes messages in the negotiated protocol
+pub(crate) struct Connection<S: Read + Write> {
+    stream: BufReader<S>,
+    protocol: Protocol,
+    output: Vec<u8>,
+}
+
+impl<S: Read + Write> Connection<S> {
+    /// open the client side of a connection and negotiate `protocol` with the server
+    pub(crate) fn connect(mut stream: S, protocol: Protocol) -> Result<Self> {
+        if protocol == Protocol::Binary {
+            stream.write_all(&[BINARY_MAGIC, BINARY_VERSION])?;
+            stream.flush()?;
+            let mut reply = [0; 2];
+            stream.read_exact(&mut reply)?;
+            if reply != [BINARY_MAGIC, BINARY_VERSION] {
+                return Err(KVStoreError::UnsupportedProtocol(reply[1]));
+            }
+        }
+        Ok(Connection {
+            stream: BufReader::new(stream),
+            protocol,
+            output: Vec::new(),
+        })
+    }
+
+    /// open the server side of a connection, detecting the protocol from the first bytes.
+    /// Return None if the peer closed the connection before sending anything.
+    pub(crate) fn accept(stream: S) -> Result<Option<Self>> {
+        let mut stream = BufReader::new(stream);
+        let protocol = match stream.fill_buf()?.first() {
+            None => return Ok(None),
+            Some(&BINARY_MAGIC) => {
+                let mut hello = [0; 2];
+                stream.read_exact(&mut hello)?;
+                stream
+                    .get_mut()
+                    .write_all(&[BINARY_MAGIC, BINARY_VERSION])?;
+                stream.get_mut().flush()?;
+                if hello[1] != BINARY_VERSION {
+                    return Err(KVStoreError::UnsupportedProtocol(hello[1]));
+                }
+                Protocol::Binary
+            }
+            Some(_) => Protocol::Json,
+        };
+        Ok(Some(Connection {
+            stream,
+            protocol,
+            output: Vec::new(),
+        }))
+    }
+
+    /// the protocol spoken on this connection
+    pub(crate) fn protocol(&self) -> Protocol {
+        self.protocol
+    }
+
+    /// buffer a message, it is sent on the next `flush`
+    pub(crate) fn write<T: Serialize>(&mut self, message: &T) -> Result<()> {
+        encode_message(self.protocol, message, &mut self.output)
+    }
+
+    /// send all buffered messages
+    pub(crate) fn flush(&mut self) -> Result<()> {
+        self.flush_until(self.output.len())
+    }
+
+    /// the number of buffered bytes not sent yet
+    pub(crate) fn buffered(&self) -> usize {
+        self.output.len()
+    }
+
+    /// send the first `end` buffered bytes, keeping the rest buffered
+    pub(crate) fn flush_until(&mut self, end: usize) -> Result<()> {
+        let stream = self.stream.get_mut();
+        stream.write_all(&self.output[..end])?;
+        stream.flush()?;
+        self.output.drain(..end);
+        Ok(())
+    }
+
+    /// read the next message. Return None if the peer closed the connection between messages.
+    pub(crate) fn read<T: DeserializeOwned>(&mut self) -> Result<Option<T>> {
+        if self.stream.fill_buf()?.is_empty() {
+            return Ok(None);
+        }
+        match self.protocol {
+            Protocol::Json => {
+                // requests and responses are JSON objects or strings, so the deserializer
+                // never reads past the end of the current message
+                T::deserialize(&mut Deserializer::from_reader(&mut self.stream))
+                    .map(Some)
+                    .map_err(|err| {
+                        if err.is_io() {
+                            KVStoreError::Io(io::Error::from(err))
+                        } else {
+                            KVStoreError::Serde(err)
+                        }
+                    })
+            }
+            Protocol::Binary => {
+                let mut length = [0; 4];
+                self.stream.read_exact(&mut length)?;
+                let length = u32::from_be_bytes(length) as usize;
+                if length > MAX_FRAME_SIZE {
+                    return Err(KVStoreError::FrameTooLarge(length));
+                }
+                let mut payload = vec![0; length];
+                self.stream.read_exact(&mut payload)?;
+                Ok(Some(bincode::deserialize(&payload)?))
+            }
+        }
+    }
+}
+
+/// The async counterpart of `Connection`, speaking the same protocols.
+#[cfg(feature = "async")]
+pub(crate) struct AsyncConnection<S: AsyncRead + AsyncWrite + Unpin> {
+    stream: S,
+    protocol: Protocol,
+    /// bytes read from the stream which are not decoded yet
+    input: Vec<u8>,
+    /// how far the JSON message at the start of `input` was scanned
+    json: JsonScanner,
+    output: Vec<u8>,
+}
+
+/// Finds the end of the JSON message at the start of a buffer which grows with every read,
+/// keeping its state between reads so each byte is only looked at once. Messages are JSON
+/// objects or strings.
+#[cfg(feature = "async")]
+#[derive(Default)]
+struct JsonScanner {
+    /// number of bytes scanned
+    offset: usize,
+    /// nesting depth of objects and arrays
+    depth: usize,
+    in_string: bool,
+    /// whether the previous byte of a string was an unescaped backslash
+    escaped: bool,
+}
+
+#[cfg(feature = "async")]
+impl JsonScanner {
+    /// Scan the bytes of `input` added since the last call. Return the length of the first
+    /// message once it is complete.
+    fn scan(&mut self, input: &[u8]) -> Option<usize> {
+        while let Some(&byte) = input.get(self.offset) {
+            self.offset += 1;
+            if self.in_string {
+                match byte {
+                    _ if self.escaped => self.escaped = false,
+                    b'\\' => self.escaped = true,
+                    b'"' => {
+                        self.in_string = false;
+                        if self.depth == 0 {
+                            return Some(self.offset);
+                        }
+                    }
+                    _ => {}
+                }
+                continue;
+            }
+            match byte {
+                b'"' => self.in_string = true,
+                b'{' | b'[' => self.depth += 1,
+                b'}' | b']' => {
+                    self.depth = self.depth.saturating_sub(1);
+                    if self.depth == 0 {
+                        return Some(self.offset);
+                    }
+                }
+                _ if byte.is_ascii_whitespace() || self.depth > 0 => {}
+                // not a message, which the parser reports
+                _ => return Some(self.offset),
+            }
+        }
+        None
+    }
+}
+
+#[cfg(feature = "async")]
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncConnection<S> {
+    /// open the client side of a connection and negotiate `protocol` with the server
+    pub(crate) async fn connect(mut stream: S, protocol: Protocol) -> Result<Self> {
+        if protocol == Protocol::Binary {
+            stream.write_all(&[BINARY_MAGIC, BINARY_VERSION]).await?;
+            stream.flush().await?;
+            let mut reply = [0; 2];
+            stream.read_exact(&mut reply).await?;
+            if reply != [BINARY_MAGIC, BINARY_VERSION] {
+                return Err(KVStoreError::UnsupportedProtocol(reply[1]));
+            }
+        }
+        Ok(AsyncConnection {
+            stream,
+            protocol,
+            input: Vec::new(),
+            json: JsonScanner::default(),
+            output: Vec::new(),
+        })
+    }
+
+    /// open the server side of a connection, detecting the protocol from the first bytes.
+    /// Return None if the peer closed the connection before sending anything.
+    pub(crate) async fn accept(stream: S) -> Result<Option<Self>> {
+        let mut connection = AsyncConnection {
+            stream,
+            protocol: Protocol::Json,
+            input: Vec::new(),
+            json: JsonScanner::default(),
+            output: Vec::new(),
+        };
+        if connection.fill().await? == 0 {
+            return Ok(None);
+        }
+        if connection.input[0] == BINARY_MAGIC {
+            while connection.input.len() < 2 {
+                if connection.fill().await? == 0 {
+                    return Ok(None);
+                }
+            }
+            let version = connection.input[1];
+            connection.input.drain(..2);
+            connection
+                .stream
+                .write_all(&[BINARY_MAGIC, BINARY_VERSION])
+                .await?;
+            connection.stream.flush().await?;
+            if version != BINARY_VERSION {
+                return Err(KVStoreError::UnsupportedProtocol(version));
+            }
+            connection.protocol = Protocol::Binary;
+        }
+        Ok(Some(connection))
+    }
+
+    /// the protocol spoken on this connection
+    pub(crate) fn protocol(&self) -> Protocol {
+        self.protocol
+    }
+
+    /// buffer a message, it is sent on the next `flush`
+    pub(crate) fn write<T: Serialize>(&mut self, message: &T) -> Result<()> {
+        encode_message(self.protocol, message, &mut self.output)
+    }
+
+    /// send all buffered messages
+    pub(crate) async fn flush(&mut self) -> Result<()> {
+        self.stream.write_all(&self.output).await?;
+        self.stream.flush().await?;
+        self.output.clear();
+        Ok(())
+    }
+
+    /// read the next message. Return None if the peer closed the connection between messages.
+    pub(crate) async fn read<T: DeserializeOwned>(&mut self) -> Result<Option<T>> {
+        loop {
+            if let Some(message) = self.decode()? {
+                return Ok(Some(message));
+            }
+            if self.fill().await? == 0 {
+                if self.input.iter().all(u8::is_ascii_whitespace) {
+                    return Ok(None);
+                }
+                return Err(KVStoreError::Io(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "connection closed in the middle of a message",
+                )));
+            }
+        }
+    }
+
+    /// Decode the next message if it was read completely.
+    fn decode<T: DeserializeOwned>(&mut self) -> Result<Option<T>> {
+        let (message, length) = match self.protocol {
+            Protocol::Json => {
+                // parsed only once the scanner found a whole message, not after every read
+                if self.json.scan(&self.input).is_none() {
+                    if self.input.len() > MAX_FRAME_SIZE {
+                        return Err(KVStoreError::FrameTooLarge(self.input.len()));
+                    }
+                    return Ok(None);
+                }
+                let mut messages = Deserializer::from_slice(&self.input).into_iter::<T>();
+                match messages.next() {
+                    Some(Ok(message)) => {
+                        self.json = JsonScanner::default();
+                        (message, messages.byte_offset())
+                    }
+                    Some(Err(err)) if err.is_eof() => return Ok(None),
+                    Some(Err(err)) => return Err(err.into()),
+                    None => return Ok(None),
+                }
+            }
+            Protocol::Binary => {
+                let Some(header) = self.input.get(..4) else {
+                    return Ok(None);
+                };
+                let length = u32::from_be_bytes(header.try_into().unwrap()) as usize;
+                if length > MAX_FRAME_SIZE {
+                    return Err(KVStoreError::FrameTooLarge(length));
+                }
+                match self.input.get(4..4 + length) {
+                    Some(payload) => (bincode::deserialize(payload)?, 4 + length),
+                    None => return Ok(None),
+                }
+            }
+        };
+        self.input.drain(..length);
+        Ok(Some(message))
+    }
+
+    /// Read more bytes from the stream. Return how many were read, 0 at the end of the stream.
+    async fn fill(&mut self) -> Result<usize> {
+        let mut buf = [0; 8 * 1024];
+        let read = self.stream.read(&mut buf).await?;
+        self.input.extend_from_slice(&buf[..read]);
+        Ok(read)
+    }
 }
@@ -1,102 +1,411 @@
+use crate::proto::Connection;
 use crate::thread_pool::ThreadPool;
-use crate::Result;
+use crate::transport::{Listener, Stream};
+use crate::{AccessControl, KVStoreError, Result, ServerTlsConfig};
 use crate::{KvsEngine, Request, Response};
-use log::{debug, error};
-use serde::Deserialize;
-use serde_json::Deserializer;
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::fmt;
-use std::io::BufReader;
-use std::net::{TcpListener, TcpStream};
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
-use std::time::SystemTime;
+use std::io;
+use std::net::Shutdown;
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, SystemTime};
+
+/// how long a connection may stay idle before the server closes it
+pub(crate) const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+/// how long a shutdown waits for open connections to finish their requests
+pub(crate) const DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
 
 /// a generic KvServer which supports pluggable storage engines
 pub struct KvServer<E: KvsEngine, P: ThreadPool> {
     engine: E,
     pool: P,
-    is_stop: Arc<AtomicBool>,
+    shutdown: ShutdownHandle,
+    connections: Arc<Connections>,
+    tls: Option<ServerTlsConfig>,
+    acl: Option<Arc<AccessControl>>,
+    backup_dir: Option<Arc<PathBuf>>,
+    max_connections: Option<usize>,
 }
 
 impl<E: KvsEngine, P: ThreadPool> KvServer<E, P> {
-    /// create server with engine
-    pub fn new(engine: E, pool: P, is_stop: Arc<AtomicBool>) -> Self {
+    /// create server with engine. Every open connection holds a thread of `pool` until it is
+    /// closed, so connections beyond the number of threads wait until one closes unless
+    /// `max_connections` refuses them.
+    pub fn new(engine: E, pool: P) -> Self {
         KvServer {
             engine,
             pool,
-            is_stop,
+            shutdown: ShutdownHandle::default(),
+            connections: Arc::new(Connections::default()),
+            tls: None,
+            acl: None,
+            backup_dir: None,
+            max_connections: None,
         }
     }
 
-    /// serve at addr to handle requests
-    pub fn serve(&mut self, addr: &String) -> Result<()> {
-        let listener = TcpListener::bind(addr)?;
-        for stream in listener.incoming() {
-            if self.is_stop.load(Ordering::SeqCst) {
-                break;
+    /// Serve every connection over TLS with the given settings.
+    pub fn tls(mut self, tls: ServerTlsConfig) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// Restrict every connection to the keys `acl` grants it, starting with the anonymous
+    /// permissions until it authenticates.
+    pub fn access_control(mut self, acl: AccessControl) -> Self {
+        self.acl = Some(Arc::new(acl));
+        self
+    }
+
+    /// Write the snapshots requested by clients into `dir`. Without it backups are refused.
+    pub fn backup_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.backup_dir = Some(Arc::new(dir.into()));
+        self
+    }
+
+    /// Close new connections right away while `max` connections are open, rather than let
+    /// them wait for a pool thread. Set it to the number of pool threads so every connection
+    /// which is accepted is served.
+    pub fn max_connections(mut self, max: usize) -> Self {
+        self.max_connections = Some(max);
+        self
+    }
+
+    /// Return a handle which shuts the server down from another thread.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Serve at addr to handle requests until a shutdown is requested through a
+    /// `ShutdownHandle`. Then stop accepting, let open connections finish their current
+    /// request, waiting for them for a while, and flush the engine before returning.
+    ///
+    /// addr is either a TCP `host:port` or `unix:<PATH>`, for a Unix domain socket at PATH
+    /// which is removed again when the server stops.
+    pub fn serve(&mut self, addr: &str) -> Result<()> {
+        let listener = Listener::bind(addr)?;
+        *self.shutdown.wake_addr.lock().unwrap() = Some(listener.local_addr()?);
+        while !self.shutdown.is_shutdown() {
+            let stream = match listener.accept() {
+                // the connection which wakes the listener up for a shutdown is not served
+                Ok(_) if self.shutdown.is_shutdown() => break,
+                Ok(stream) => stream,
+                Err(err) => {
+                    error!(
+                        "Unexpected error occurs when serving incoming request {:?}",
+                        err
+                    );
+                    continue;
+                }
+            };
+            if self
+                .max_connections
+                .is_some_and(|max| self.connections.len() >= max)
+            {
+                warn!("Refusing a connection, {} are open", self.connections.len());
+                let _ = stream.shutdown(Shutdown::Both);
+                continue;
             }
+            let registered = match Connections::register(&self.connections, &stream) {
+                Ok(registered) => registered,
+                Err(err) => {
+                    error!("Unable to register connection: {:?}", err);
+                    continue;
+                }
+            };
             let engine = self.engine.clone();
-            self.pool.spawn(move || match stream {
-                Ok(stream) => {
-                    if let Err(err) = handle_connection(engine, stream) {
-                        error!("Unexpected error occurs when serving request: {:?}", err)
-                    }
+            let tls = self.tls.clone();
+            let acl = self.acl.clone();
+            let backup_dir = self.backup_dir.clone();
+            self.pool.spawn(move || {
+                let _registered = registered;
+                if let Err(err) = handle_connection(engine, stream, tls, acl, backup_dir) {
+                    error!("Unexpected error occurs when serving request: {:?}", err)
                 }
-                Err(err) => error!(
-                    "Unexpected error occurs when serving incoming request {:?}",
-                    err
-                ),
             })
         }
-        Ok(())
+
+        info!("Shutting down, draining open connections");
+        self.shutdown.wake_addr.lock().unwrap().take();
+        drop(listener);
+        if !self.connections.drain(DRAIN_TIMEOUT) {
+            warn!("Connections still open after {:?}", DRAIN_TIMEOUT);
+        }
+        self.engine.flush()
     }
 }
 
-fn handle_connection<E: KvsEngine>(engine: E, mut stream: TcpStream) -> Result<()> {
-    let request =
-        Request::deserialize(&mut Deserializer::from_reader(BufReader::new(&mut stream)))?;
-
-    let now = SystemTime::now();
-    debug!("Request: {:?}", &request);
+/// Requests the shutdown of a `KvServer`. Clones share the request.
+#[derive(Clone, Default)]
+pub struct ShutdownHandle {
+    is_stop: Arc<AtomicBool>,
+    /// the address the listener of a serving `KvServer` can be reached at, which is connected
+    /// to once to wake up its blocking accept
+    wake_addr: Arc<Mutex<Option<String>>>,
+    /// wakes up a serving `AsyncKvServer` waiting for a connection
+    #[cfg(feature = "async")]
+    pub(crate) notify: Arc<tokio::sync::Notify>,
+}
 
-    let response;
-    match request {
-        Request::SET(key, value) => {
-            match engine.set(key, value) {
-                Ok(_) => response = Response::Ok(None),
-                Err(err) => response = Response::Err(format!("{}", err)),
-            };
-        }
-        Request::RM(key) => {
-            match engine.remove(key) {
-                Ok(_) => response = Response::Ok(None),
-                Err(err) => response = Response::Err(format!("{}", err)),
-            };
+impl ShutdownHandle {
+    /// Ask the server to shut down. `KvServer::serve` returns once it has.
+    pub fn shutdown(&self) {
+        self.is_stop.store(true, Ordering::SeqCst);
+        #[cfg(feature = "async")]
+        self.notify.notify_waiters();
+        if let Some(addr) = self.wake_addr.lock().unwrap().as_deref() {
+            if let Err(err) = Stream::connect(addr) {
+                warn!("Unable to wake up the listener at {}: {:?}", addr, err);
+            }
         }
-        Request::GET(key) => {
-            match engine.get(key) {
-                Ok(value) => response = Response::Ok(value),
-                Err(err) => response = Response::Err(format!("{}", err)),
-            };
+    }
+
+    /// Return true if a shutdown was requested.
+    pub fn is_shutdown(&self) -> bool {
+        self.is_stop.load(Ordering::SeqCst)
+    }
+}
+
+/// The open connections of a server, so a shutdown can close them and wait for them.
+#[derive(Default)]
+struct Connections {
+    next_id: AtomicU64,
+    /// a clone of the stream of every open connection by id
+    streams: Mutex<HashMap<u64, Stream>>,
+    closed: Condvar,
+}
+
+impl Connections {
+    /// Add a connection, which is removed again when the returned guard is dropped.
+    fn register(connections: &Arc<Connections>, stream: &Stream) -> Result<Registered> {
+        let id = connections.next_id.fetch_add(1, Ordering::SeqCst);
+        let clone = stream.try_clone()?;
+        connections.streams.lock().unwrap().insert(id, clone);
+        Ok(Registered {
+            connections: Arc::clone(connections),
+            id,
+        })
+    }
+
+    /// Return the number of open connections.
+    fn len(&self) -> usize {
+        self.streams.lock().unwrap().len()
+    }
+
+    /// Shut down the reading half of every open connection, so each one ends once its current
+    /// request is answered, and wait until they are closed. Return false if some connection
+    /// is still open after `timeout`.
+    fn drain(&self, timeout: Duration) -> bool {
+        let streams = self.streams.lock().unwrap();
+        for stream in streams.values() {
+            let _ = stream.shutdown(Shutdown::Read);
         }
+        let (streams, _) = self
+            .closed
+            .wait_timeout_while(streams, timeout, |streams| !streams.is_empty())
+            .unwrap();
+        streams.is_empty()
     }
+}
+
+/// Removes a connection from `Connections` once it is closed.
+struct Registered {
+    connections: Arc<Connections>,
+    id: u64,
+}
+
+impl Drop for Registered {
+    fn drop(&mut self) {
+        self.connections.streams.lock().unwrap().remove(&self.id);
+        self.connections.closed.notify_all();
+    }
+}
+
+/// serve requests on one connection until the peer closes it or it stays idle for too long
+fn handle_connection<E: KvsEngine>(
+    engine: E,
+    stream: Stream,
+    tls: Option<ServerTlsConfig>,
+    acl: Option<Arc<AccessControl>>,
+    backup_dir: Option<Arc<PathBuf>>,
+) -> Result<()> {
+    stream.set_read_timeout(Some(IDLE_TIMEOUT))?;
+    let stream = match tls {
+        Some(tls) => stream.accept_tls(tls.config)?,
+        None => stream,
+    };
+    let mut connection = match Connection::accept(stream) {
+        Ok(Some(connection)) => connection,
+        Ok(None) => return Ok(()),
+        Err(err) if is_timeout(&err) => return Ok(()),
+        Err(err) => return Err(err),
+    };
+    debug!("Accept {:?} connection", connection.protocol());
+
+    // None while access is not controlled
+    let mut permissions = acl.as_ref().map(|acl| acl.anonymous.clone());
+    loop {
+        let request = match connection.read::<Request>() {
+            Ok(Some(request)) => request,
+            Ok(None) => return Ok(()),
+            Err(err) if is_timeout(&err) => {
+                debug!("Close idle connection");
+                return Ok(());
+            }
+            Err(err) => return Err(err),
+        };
 
-    debug!("Response: {:?}, {:?}", &response, now.elapsed());
+        let now = SystemTime::now();
+        log_request(&request);
 
-    serde_json::to_writer(stream, &response)?;
+        let response = match (&acl, request) {
+            (Some(acl), Request::AUTH(user, password)) => {
+                match acl.authenticate(&user, &password) {
+                    Some(granted) => {
+                        permissions = Some(granted.clone());
+                        Response::Ok(None)
+                    }
+                    None => {
+                        warn!("Authentication failed for user {:?}", user);
+                        // the connection no longer acts as the user it authenticated as before
+                        permissions = Some(acl.anonymous.clone());
+                        Response::PermissionDenied("invalid username or password".to_owned())
+                    }
+                }
+            }
+            (_, request) => match permissions.as_ref().map(|p| p.check(&request)) {
+                Some(Err(reason)) => Response::PermissionDenied(reason),
+                _ => process_request(
+                    &engine,
+                    request,
+                    backup_dir.as_deref().map(PathBuf::as_path),
+                ),
+            },
+        };
 
-    Ok(())
+        debug!("Response: {:?}, {:?}", &response, now.elapsed());
+
+        connection.write(&response)?;
+        connection.flush()?;
+    }
+}
+
+fn is_timeout(err: &KVStoreError) -> bool {
+    matches!(err, KVStoreError::Io(err) if matches!(
+        err.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    ))
+}
+
+/// log a request at debug level, leaving out passwords
+pub(crate) fn log_request(request: &Request) {
+    match request {
+        Request::AUTH(user, _) => debug!("Request: AUTH({:?})", user),
+        request => debug!("Request: {:?}", request),
+    }
+}
+
+/// Answer a request with the engine. Snapshots are written into `backup_dir`, and refused
+/// without one.
+pub(crate) fn process_request<E: KvsEngine>(
+    engine: &E,
+    request: Request,
+    backup_dir: Option<&Path>,
+) -> Response {
+    match request {
+        Request::SET(key, value) => match engine.set_bytes(key, value) {
+            Ok(_) => Response::Ok(None),
+            Err(err) => Response::Err(format!("{}", err)),
+        },
+        Request::SETEX(key, value, ttl) => {
+            match engine.set_with_ttl(key, value, Duration::from_millis(ttl)) {
+                Ok(_) => Response::Ok(None),
+                Err(err) => Response::Err(format!("{}", err)),
+            }
+        }
+        Request::RM(key) => match engine.remove_bytes(&key) {
+            Ok(_) => Response::Ok(None),
+            Err(err) => Response::Err(format!("{}", err)),
+        },
+        Request::GET(key) => match engine.get_bytes(&key) {
+            Ok(value) => Response::Ok(value),
+            Err(err) => Response::Err(format!("{}", err)),
+        },
+        Request::CAS(key, expected, new) => match engine.compare_and_swap(key, expected, new) {
+            Ok(_) => Response::Ok(None),
+            Err(KVStoreError::CompareAndSwapFailed(current)) => Response::CasFailed(current),
+            Err(err) => Response::Err(format!("{}", err)),
+        },
+        Request::BATCH(batch) => match engine.write_batch(batch) {
+            Ok(_) => Response::Ok(None),
+            Err(err) => Response::Err(format!("{}", err)),
+        },
+        Request::SCAN(start, end, limit) => match engine.scan(&start, end.as_deref(), limit) {
+            Ok(pairs) => Response::Entries(pairs),
+            Err(err) => Response::Err(format!("{}", err)),
+        },
+        Request::COMPACT => match engine.compact() {
+            Ok(_) => Response::Ok(None),
+            Err(err) => Response::Err(format!("{}", err)),
+        },
+        Request::BACKUP(dest) => {
+            match backup_path(backup_dir, &dest).and_then(|dest| engine.snapshot(&dest)) {
+                Ok(_) => Response::Ok(None),
+                Err(err) => Response::Err(format!("{}", err)),
+            }
+        }
+        // a server without access control lets every connection do anything
+        Request::AUTH(..) => Response::Ok(None),
+    }
+}
+
+/// Resolve the destination of a backup inside `backup_dir`. It must be a relative path which
+/// stays inside the directory.
+fn backup_path(backup_dir: Option<&Path>, dest: &str) -> Result<PathBuf> {
+    let backup_dir = backup_dir.ok_or_else(|| {
+        KVStoreError::CommonStringError("backups are not enabled on this server".to_owned())
+    })?;
+    let dest = Path::new(dest);
+    let mut components = dest.components().peekable();
+    if components.peek().is_none()
+        || !components.all(|component| matches!(component, Component::Normal(_)))
+    {
+        return Err(KVStoreError::CommonStringError(format!(
+            "{:?} is not a directory name inside the backup directory",
+            dest
+        )));
+    }
+    Ok(backup_dir.join(dest))
 }
 
 /// Indicates the type of engine
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum EngineType {
     /// for KvStore
+    #[serde(rename = "kvs")]
     KvStore,
     /// for SledKvsEngine
+    #[serde(rename = "sled")]
     SledKvsEngine,
 }
 
+impl FromStr for EngineType {
+    type Err = KVStoreError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "kvs" => Ok(EngineType::KvStore),
+            "sled" => Ok(EngineType::SledKvsEngine),
+            _ => Err(KVStoreError::UnknownEngineType),
+        }
+    }
+}
+
 impl fmt::Display for EngineType {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
@@ -0,0 +1,169 @@
+use crate::transport::unix_path;
+use crate::{KVStoreError, Result};
+use rustls::crypto::{ring, CryptoProvider};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{ClientConfig, RootCertStore, ServerConfig};
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::Path;
+use std::sync::Arc;
+
+/** TLS settings of a `KvServer`: its certificate and, for mutual TLS, the CAs whose client
+certificates it accepts.
+# Example
+```no_run
+use blaze_turbo::{KvServer, KvStore, Result, ServerTlsConfig, SharedQueueThreadPool, ThreadPool};
+# fn try_main() -> Result<()> {
+let tls = ServerTlsConfig::new("server.pem", "server.key")?;
+let engine = KvStore::open("kvs")?;
+let mut server = KvServer::new(engine, SharedQueueThreadPool::new(4)?).tls(tls);
+server.serve("127.0.0.1:4000")?;
+# Ok(())
+# }
+```
+ */
+#[derive(Clone)]
+pub struct ServerTlsConfig {
+    pub(crate) config: Arc<ServerConfig>,
+}
+
+impl ServerTlsConfig {
+    /// Serve with the certificate chain and private key in the given PEM files, without
+    /// asking clients for a certificate.
+    pub fn new(cert: impl AsRef<Path>, key: impl AsRef<Path>) -> Result<Self> {
+        let config = ServerConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_single_cert(load_certs(cert.as_ref())?, load_key(key.as_ref())?)?;
+        Ok(ServerTlsConfig {
+            config: Arc::new(config),
+        })
+    }
+
+    /// Like `new`, but require every client to present a certificate issued by one of the
+    /// CAs in the PEM file `client_ca`.
+    pub fn with_client_auth(
+        cert: impl AsRef<Path>,
+        key: impl AsRef<Path>,
+        client_ca: impl AsRef<Path>,
+    ) -> Result<Self> {
+        let roots = Arc::new(load_roots(client_ca.as_ref())?);
+        let verifier = WebPkiClientVerifier::builder_with_provider(roots, provider())
+            .build()
+            .map_err(|err| rustls::Error::General(err.to_string()))?;
+        let config = ServerConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()?
+            .with_client_cert_verifier(verifier)
+            .with_single_cert(load_certs(cert.as_ref())?, load_key(key.as_ref())?)?;
+        Ok(ServerTlsConfig {
+            config: Arc::new(config),
+        })
+    }
+}
+
+/** TLS settings of a `Client`: the CAs it trusts and, for mutual TLS, its own certificate.
+# Example
+```no_run
+use blaze_turbo::{Client, ClientTlsConfig, Protocol, Request, Result};
+# fn try_main() -> Result<()> {
+let tls = ClientTlsConfig::new("ca.pem")?;
+let mut client = Client::connect_tls("127.0.0.1:4000", Protocol::Binary, &tls)?;
+client.request(&Request::GET(b"1".to_vec()))?;
+# Ok(())
+# }
+```
+ */
+#[derive(Clone)]
+pub struct ClientTlsConfig {
+    pub(crate) config: Arc<ClientConfig>,
+    pub(crate) server_name: Option<ServerName<'static>>,
+}
+
+impl ClientTlsConfig {
+    /// Trust servers whose certificate was issued by one of the CAs in the PEM file `ca`.
+    pub fn new(ca: impl AsRef<Path>) -> Result<Self> {
+        let config = ClientConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(load_roots(ca.as_ref())?)
+            .with_no_client_auth();
+        Ok(ClientTlsConfig {
+            config: Arc::new(config),
+            server_name: None,
+        })
+    }
+
+    /// Like `new`, but also present the certificate chain and private key in the given PEM
+    /// files to servers which require mutual TLS.
+    pub fn with_client_cert(
+        ca: impl AsRef<Path>,
+        cert: impl AsRef<Path>,
+        key: impl AsRef<Path>,
+    ) -> Result<Self> {
+        let config = ClientConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(load_roots(ca.as_ref())?)
+            .with_client_auth_cert(load_certs(cert.as_ref())?, load_key(key.as_ref())?)?;
+        Ok(ClientTlsConfig {
+            config: Arc::new(config),
+            server_name: None,
+        })
+    }
+
+    /// Verify the server certificate against this name instead of the host of the address
+    /// connected to, or `localhost` for `unix:<PATH>` addresses.
+    pub fn server_name(mut self, name: &str) -> Result<Self> {
+        self.server_name = Some(parse_server_name(name)?);
+        Ok(self)
+    }
+
+    /// The name to verify the certificate of the server at addr against.
+    pub(crate) fn server_name_for(&self, addr: &str) -> Result<ServerName<'static>> {
+        if let Some(name) = &self.server_name {
+            return Ok(name.clone());
+        }
+        if unix_path(addr).is_some() {
+            return parse_server_name("localhost");
+        }
+        let host = match addr.rsplit_once(':') {
+            Some((host, _)) => host.trim_start_matches('[').trim_end_matches(']'),
+            None => addr,
+        };
+        parse_server_name(host)
+    }
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+fn parse_server_name(name: &str) -> Result<ServerName<'static>> {
+    ServerName::try_from(name.to_owned()).map_err(|err| invalid_input(err.to_string()))
+}
+
+fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certs = rustls_pemfile::certs(&mut reader).collect::<io::Result<Vec<_>>>()?;
+    if certs.is_empty() {
+        return Err(invalid_input(format!("No certificate in {:?}", path)));
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &Path) -> Result<PrivateKeyDer<'static>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    rustls_pemfile::private_key(&mut reader)?
+        .ok_or_else(|| invalid_input(format!("No private key in {:?}", path)))
+}
+
+fn load_roots(path: &Path) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots.add(cert)?;
+    }
+    Ok(roots)
+}
+
+fn invalid_input(message: String) -> KVStoreError {
+    KVStoreError::Io(io::Error::new(io::ErrorKind::InvalidInput, message))
+}
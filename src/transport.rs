@@ -0,0 +1,239 @@
+use crate::{ClientTlsConfig, Result};
+use rustls::{ClientConnection, ServerConfig, ServerConnection, StreamOwned};
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::time::Duration;
+#[cfg(unix)]
+use {
+    std::fs,
+    std::os::unix::fs::FileTypeExt,
+    std::os::unix::net::{UnixListener, UnixStream},
+    std::path::{Path, PathBuf},
+};
+
+/// prefix of an address which names a Unix domain socket instead of a TCP host and port
+const UNIX_PREFIX: &str = "unix:";
+
+/// The path of the Unix domain socket named by `addr`, or None for a TCP address.
+pub(crate) fn unix_path(addr: &str) -> Option<&str> {
+    addr.strip_prefix(UNIX_PREFIX)
+}
+
+#[cfg(not(unix))]
+fn unix_unsupported() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Unix domain sockets are not supported on this platform",
+    )
+}
+
+/// A listening socket: TCP for `host:port` addresses, a Unix domain socket for
+/// `unix:<PATH>` ones.
+pub(crate) enum Listener {
+    Tcp(TcpListener),
+    /// the listener and its socket file, which is removed when the listener is dropped
+    #[cfg(unix)]
+    Unix(UnixListener, PathBuf),
+}
+
+impl Listener {
+    /// Listen at addr. A socket file left behind by a server which did not shut down cleanly
+    /// is replaced, one a server is still listening on is not, and neither is any other file.
+    pub(crate) fn bind(addr: &str) -> Result<Listener> {
+        match unix_path(addr) {
+            None => Ok(Listener::Tcp(TcpListener::bind(addr)?)),
+            #[cfg(unix)]
+            Some(path) => {
+                if fs::symlink_metadata(path).is_ok() {
+                    if !is_socket(Path::new(path)) {
+                        return Err(io::Error::new(
+                            io::ErrorKind::AddrInUse,
+                            format!("{:?} exists and is not a socket", path),
+                        )
+                        .into());
+                    }
+                    if UnixStream::connect(path).is_ok() {
+                        return Err(io::Error::from(io::ErrorKind::AddrInUse).into());
+                    }
+                    fs::remove_file(path)?;
+                }
+                Ok(Listener::Unix(
+                    UnixListener::bind(path)?,
+                    PathBuf::from(path),
+                ))
+            }
+            #[cfg(not(unix))]
+            Some(_) => Err(unix_unsupported().into()),
+        }
+    }
+
+    /// Return an address a client can connect to this listener at. A TCP listener bound to
+    /// every interface is reached over loopback.
+    pub(crate) fn local_addr(&self) -> io::Result<String> {
+        match self {
+            Listener::Tcp(listener) => {
+                let mut addr = listener.local_addr()?;
+                if addr.ip().is_unspecified() {
+                    addr.set_ip(match addr.ip() {
+                        IpAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+                        IpAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+                    });
+                }
+                Ok(addr.to_string())
+            }
+            #[cfg(unix)]
+            Listener::Unix(_, path) => Ok(format!("{}{}", UNIX_PREFIX, path.display())),
+        }
+    }
+
+    pub(crate) fn accept(&self) -> io::Result<Stream> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, _) = listener.accept()?;
+                stream.set_nodelay(true)?;
+                Ok(Stream::Tcp(stream))
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener, _) => {
+                listener.accept().map(|(stream, _)| Stream::Unix(stream))
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Listener::Unix(_, path) = self {
+            if is_socket(path) {
+                let _ = fs::remove_file(path);
+            }
+        }
+    }
+}
+
+/// Return true if there is a socket file at path, not following symlinks.
+#[cfg(unix)]
+fn is_socket(path: &Path) -> bool {
+    fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket())
+}
+
+/// A connected socket of either kind, possibly wrapped in TLS.
+pub(crate) enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+    TlsServer(Box<StreamOwned<ServerConnection, Stream>>),
+    TlsClient(Box<StreamOwned<ClientConnection, Stream>>),
+}
+
+impl Stream {
+    /// Connect to addr, a `host:port` or `unix:<PATH>` address.
+    pub(crate) fn connect(addr: &str) -> Result<Stream> {
+        match unix_path(addr) {
+            None => {
+                let stream = TcpStream::connect(addr)?;
+                // a request or chunk of pipelined requests is sent at once, so waiting to
+                // coalesce it with later writes only delays it
+                stream.set_nodelay(true)?;
+                Ok(Stream::Tcp(stream))
+            }
+            #[cfg(unix)]
+            Some(path) => Ok(Stream::Unix(UnixStream::connect(path)?)),
+            #[cfg(not(unix))]
+            Some(_) => Err(unix_unsupported().into()),
+        }
+    }
+
+    /// Connect to addr and complete a TLS handshake, so a certificate the client rejects or a
+    /// server which does not speak TLS fails the connect.
+    pub(crate) fn connect_tls(addr: &str, tls: &ClientTlsConfig) -> Result<Stream> {
+        let mut sock = Stream::connect(addr)?;
+        let mut conn = ClientConnection::new(Arc::clone(&tls.config), tls.server_name_for(addr)?)?;
+        while conn.is_handshaking() {
+            conn.complete_io(&mut sock)?;
+        }
+        Ok(Stream::TlsClient(Box::new(StreamOwned::new(conn, sock))))
+    }
+
+    /// Wrap the server side of a connection in TLS. The handshake happens on the first read.
+    pub(crate) fn accept_tls(self, config: Arc<ServerConfig>) -> Result<Stream> {
+        let conn = ServerConnection::new(config)?;
+        Ok(Stream::TlsServer(Box::new(StreamOwned::new(conn, self))))
+    }
+
+    /// Clone the socket. A TLS stream cannot be cloned as its session state is not shared.
+    pub(crate) fn try_clone(&self) -> io::Result<Stream> {
+        match self {
+            Stream::Tcp(stream) => stream.try_clone().map(Stream::Tcp),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.try_clone().map(Stream::Unix),
+            Stream::TlsServer(_) | Stream::TlsClient(_) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "a TLS stream cannot be cloned",
+            )),
+        }
+    }
+
+    pub(crate) fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.shutdown(how),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.shutdown(how),
+            Stream::TlsServer(stream) => stream.sock.shutdown(how),
+            Stream::TlsClient(stream) => stream.sock.shutdown(how),
+        }
+    }
+
+    pub(crate) fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_read_timeout(timeout),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.set_read_timeout(timeout),
+            Stream::TlsServer(stream) => stream.sock.set_read_timeout(timeout),
+            Stream::TlsClient(stream) => stream.sock.set_read_timeout(timeout),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = match self {
+            Stream::Tcp(stream) => return stream.read(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => return stream.read(buf),
+            Stream::TlsServer(stream) => stream.read(buf),
+            Stream::TlsClient(stream) => stream.read(buf),
+        };
+        // Peers close connections without a close_notify alert, and a draining server shuts
+        // the socket down under the session. Messages are framed, so a close in the middle of
+        // one is still detected by the protocol.
+        match read {
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => Ok(0),
+            read => read,
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.write(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.write(buf),
+            Stream::TlsServer(stream) => stream.write(buf),
+            Stream::TlsClient(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.flush(),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.flush(),
+            Stream::TlsServer(stream) => stream.flush(),
+            Stream::TlsClient(stream) => stream.flush(),
+        }
+    }
+}
@@ -0,0 +1,211 @@
+#![cfg(feature = "async")]
+
+use blaze_turbo::{
+    AsyncClient, AsyncKvServer, Client, KvStore, KvsEngine, Protocol, Request, Result,
+    ShutdownHandle, WriteBatch,
+};
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::task::{self, JoinHandle};
+use tokio::time;
+
+async fn start_server(addr: &'static str, dir: &TempDir) -> (ShutdownHandle, JoinHandle<()>) {
+    let engine = KvStore::open(dir.path()).unwrap();
+    let server = AsyncKvServer::new(engine);
+    let shutdown = server.shutdown_handle();
+    let handle = tokio::spawn(async move {
+        server.serve(addr).await.unwrap();
+    });
+    time::sleep(Duration::from_millis(500)).await;
+    (shutdown, handle)
+}
+
+async fn stop_server(shutdown: ShutdownHandle, handle: JoinHandle<()>) {
+    shutdown.shutdown();
+    handle.await.unwrap();
+}
+
+// The async client should support every kind of request in both protocols
+#[tokio::test(flavor = "multi_thread")]
+async fn async_requests() -> Result<()> {
+    let addr = "127.0.0.1:4201";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (shutdown, handle) = start_server(addr, &temp_dir).await;
+
+    for protocol in [Protocol::Json, Protocol::Binary] {
+        let mut client = AsyncClient::connect(addr, protocol).await?;
+        for i in 0..100 {
+            client
+                .request(&Request::SET(
+                    format!("key{:02}", i).into_bytes(),
+                    format!("value{}", i).into_bytes(),
+                ))
+                .await?;
+        }
+        assert_eq!(
+            client.request(&Request::GET(b"key42".to_vec())).await?,
+            Some(b"value42".to_vec())
+        );
+        assert_eq!(client.scan_prefix("key9", Some(3)).await?.len(), 3);
+
+        let mut batch = WriteBatch::new();
+        batch.remove(b"key00".to_vec());
+        batch.set(b"key100".to_vec(), b"value100".to_vec());
+        client.request(&Request::BATCH(batch)).await?;
+        assert_eq!(
+            client.request(&Request::GET(b"key00".to_vec())).await?,
+            None
+        );
+        assert!(client
+            .request(&Request::RM(b"key00".to_vec()))
+            .await
+            .is_err());
+
+        // the connection is still usable after an error response
+        assert_eq!(
+            client.request(&Request::GET(b"key100".to_vec())).await?,
+            Some(b"value100".to_vec())
+        );
+        client.request(&Request::RM(b"key100".to_vec())).await?;
+    }
+
+    stop_server(shutdown, handle).await;
+    Ok(())
+}
+
+// The async server should speak the same protocols as KvServer, to blocking clients and plain
+// JSON peers alike, and serve many connections at once
+#[tokio::test(flavor = "multi_thread")]
+async fn async_server_compatibility() -> Result<()> {
+    let addr = "127.0.0.1:4202";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (shutdown, handle) = start_server(addr, &temp_dir).await;
+
+    let mut clients = Vec::new();
+    for i in 0..50 {
+        clients.push(tokio::spawn(async move {
+            let mut client = AsyncClient::new(addr).await?;
+            let key = format!("key{}", i).into_bytes();
+            client
+                .request(&Request::SET(key.clone(), b"value".to_vec()))
+                .await?;
+            client.request(&Request::GET(key)).await
+        }));
+    }
+    for client in clients {
+        assert_eq!(client.await.unwrap()?, Some(b"value".to_vec()));
+    }
+
+    let value = task::spawn_blocking(move || -> Result<_> {
+        let mut client = Client::connect(addr, Protocol::Json)?;
+        let results = client
+            .pipeline()
+            .set("key50", "value50")
+            .get("key1")
+            .execute()?;
+        Ok(results[1].as_ref().unwrap().clone())
+    })
+    .await
+    .unwrap()?;
+    assert_eq!(value, Some(b"value".to_vec()));
+
+    let mut stream = TcpStream::connect(addr).await?;
+    stream
+        .write_all(br#"{"GET":"key50"}{"GET":"key51"}"#)
+        .await?;
+    let expected = br#"{"Ok":"value50"}{"Ok":null}"#;
+    let mut received = vec![0; expected.len()];
+    stream.read_exact(&mut received).await?;
+    assert_eq!(&received[..], &expected[..]);
+
+    drop(stream);
+    stop_server(shutdown, handle).await;
+    Ok(())
+}
+
+// A shutdown should answer the requests in flight, close idle connections, stop accepting and
+// leave every acknowledged write on disk
+#[tokio::test(flavor = "multi_thread")]
+async fn async_graceful_shutdown() -> Result<()> {
+    let addr = "127.0.0.1:4203";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (shutdown, handle) = start_server(addr, &temp_dir).await;
+
+    let mut idle_client = AsyncClient::new(addr).await?;
+    idle_client
+        .request(&Request::SET(b"key0".to_vec(), b"value0".to_vec()))
+        .await?;
+
+    let writer = tokio::spawn(async move {
+        let mut client = AsyncClient::new(addr).await.unwrap();
+        let mut acknowledged = 0;
+        for i in 1.. {
+            let request = Request::SET(format!("key{}", i).into_bytes(), b"value".to_vec());
+            match client.request(&request).await {
+                Ok(_) => acknowledged = i,
+                Err(_) => break,
+            }
+        }
+        acknowledged
+    });
+    time::sleep(Duration::from_millis(200)).await;
+
+    let start = Instant::now();
+    stop_server(shutdown, handle).await;
+    assert!(start.elapsed() < Duration::from_secs(5));
+    let acknowledged = writer.await.unwrap();
+    assert!(acknowledged > 0);
+    assert!(idle_client
+        .request(&Request::GET(b"key0".to_vec()))
+        .await
+        .is_err());
+    assert!(AsyncClient::new(addr).await.is_err());
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, Some("value0".to_owned()));
+    for i in 1..=acknowledged {
+        assert_eq!(store.get(format!("key{}", i))?, Some("value".to_owned()));
+    }
+
+    Ok(())
+}
+
+// JSON messages should be decoded however they are split across reads, and a message which
+// grows past the frame size limit without ending should close the connection
+#[tokio::test(flavor = "multi_thread")]
+async fn async_json_framing() -> Result<()> {
+    let addr = "127.0.0.1:4204";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (shutdown, handle) = start_server(addr, &temp_dir).await;
+
+    let mut stream = TcpStream::connect(addr).await?;
+    let requests = br#" {"SET":["a\"}{[","x ]"]} "COMPACT" {"GET":"a\"}{["}"#;
+    for byte in requests {
+        stream.write_all(&[*byte]).await?;
+        stream.flush().await?;
+    }
+    let expected = br#"{"Ok":null}{"Ok":null}{"Ok":"x ]"}"#;
+    let mut received = vec![0; expected.len()];
+    stream.read_exact(&mut received).await?;
+    assert_eq!(&received[..], &expected[..]);
+
+    let mut stream = TcpStream::connect(addr).await?;
+    let value = vec![b'x'; 1024 * 1024];
+    let mut received = Vec::new();
+    let refused = time::timeout(Duration::from_secs(20), async {
+        let _ = stream.write_all(br#"{"SET":["big",""#).await;
+        for _ in 0..65 {
+            if stream.write_all(&value).await.is_err() {
+                break;
+            }
+        }
+        let _ = stream.read_to_end(&mut received).await;
+    });
+    refused.await.expect("connection left open");
+    assert!(received.is_empty());
+
+    stop_server(shutdown, handle).await;
+    Ok(())
+}
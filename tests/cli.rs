@@ -12,14 +12,17 @@
 /// The tests are organized into individual test functions, each covering a specific scenario.
 /// The `cli_access_server` function is a helper function used by the `cli_access_server_kvs_engine` and `cli_access_server_sled_engine` tests to test accessing the server with different engines.
 use assert_cmd::prelude::*;
+use blaze_turbo::{KVStoreError, KvStore, KvsEngine, SledKvsEngine};
 use predicates::str::{contains, is_empty};
 use std::fs::{self, File};
-use std::process::Command;
+use std::process::{Command, Stdio};
 use std::sync::mpsc;
 use std::thread;
 use std::time::Duration;
 use tempfile::TempDir;
 
+mod common;
+
 // `blaze-client` with no args should exit with a non-zero code.
 #[test]
 fn client_cli_no_args() {
@@ -36,7 +39,7 @@ fn client_cli_invalid_get() {
     // Test `get` command with no arguments
     Command::cargo_bin("blaze-client")
         .unwrap()
-        .args(&["get"])
+        .args(["get"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -44,7 +47,7 @@ fn client_cli_invalid_get() {
     // Test `get` command with extra fields
     Command::cargo_bin("blaze-client")
         .unwrap()
-        .args(&["get", "extra", "field"])
+        .args(["get", "extra", "field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -52,7 +55,7 @@ fn client_cli_invalid_get() {
     // Test `get` command with invalid address
     Command::cargo_bin("blaze-client")
         .unwrap()
-        .args(&["get", "key", "--addr", "invalid-addr"])
+        .args(["get", "key", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -60,7 +63,7 @@ fn client_cli_invalid_get() {
     // Test `get` command with unknown flag
     Command::cargo_bin("blaze-client")
         .unwrap()
-        .args(&["get", "key", "--unknown-flag"])
+        .args(["get", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -74,7 +77,7 @@ fn client_cli_invalid_set() {
     // Test `set` command with no arguments
     Command::cargo_bin("blaze-client")
         .unwrap()
-        .args(&["set"])
+        .args(["set"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -82,7 +85,7 @@ fn client_cli_invalid_set() {
     // Test `set` command with missing field
     Command::cargo_bin("blaze-client")
         .unwrap()
-        .args(&["set", "missing_field"])
+        .args(["set", "missing_field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -90,7 +93,7 @@ fn client_cli_invalid_set() {
     // Test `set` command with extra fields
     Command::cargo_bin("blaze-client")
         .unwrap()
-        .args(&["set", "key", "value", "extra_field"])
+        .args(["set", "key", "value", "extra_field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -98,7 +101,7 @@ fn client_cli_invalid_set() {
     // Test `set` command with invalid address
     Command::cargo_bin("blaze-client")
         .unwrap()
-        .args(&["set", "key", "value", "--addr", "invalid-addr"])
+        .args(["set", "key", "value", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -106,7 +109,7 @@ fn client_cli_invalid_set() {
     // Test `set` command with unknown flag
     Command::cargo_bin("blaze-client")
         .unwrap()
-        .args(&["get", "key", "--unknown-flag"])
+        .args(["get", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -117,28 +120,28 @@ fn client_cli_invalid_rm() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("blaze-client")
         .unwrap()
-        .args(&["rm"])
+        .args(["rm"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("blaze-client")
         .unwrap()
-        .args(&["rm", "extra", "field"])
+        .args(["rm", "extra", "field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("blaze-client")
         .unwrap()
-        .args(&["rm", "key", "--addr", "invalid-addr"])
+        .args(["rm", "key", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("blaze-client")
         .unwrap()
-        .args(&["rm", "key", "--unknown-flag"])
+        .args(["rm", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -149,7 +152,7 @@ fn client_cli_invalid_subcommand() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("blaze-client")
         .unwrap()
-        .args(&["unknown"])
+        .args(["unknown"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -160,7 +163,7 @@ fn client_cli_invalid_subcommand() {
 fn client_cli_version() {
     let temp_dir = TempDir::new().unwrap();
     let mut cmd = Command::cargo_bin("blaze-client").unwrap();
-    cmd.args(&["-V"])
+    cmd.args(["-V"])
         .current_dir(&temp_dir)
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
@@ -171,7 +174,7 @@ fn client_cli_version() {
 fn server_cli_version() {
     let temp_dir = TempDir::new().unwrap();
     let mut cmd = Command::cargo_bin("blaze-server").unwrap();
-    cmd.args(&["-V"])
+    cmd.args(["-V"])
         .current_dir(&temp_dir)
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
@@ -183,13 +186,14 @@ fn cli_log_configuration() {
     let stderr_path = temp_dir.path().join("stderr");
     let mut cmd = Command::cargo_bin("blaze-server").unwrap();
     let mut child = cmd
-        .args(&["--engine", "kvs", "--addr", "127.0.0.1:4001"])
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4001"])
         .current_dir(&temp_dir)
         .stderr(File::create(&stderr_path).unwrap())
         .spawn()
         .unwrap();
     thread::sleep(Duration::from_secs(1));
     child.kill().expect("server exited before killed");
+    child.wait().expect("unable to wait for server");
 
     let content = fs::read_to_string(&stderr_path).expect("unable to read from stderr file");
     assert!(content.contains(env!("CARGO_PKG_VERSION")));
@@ -197,6 +201,265 @@ fn cli_log_configuration() {
     assert!(content.contains("127.0.0.1:4001"));
 }
 
+// `blaze-server` should shut down gracefully and exit successfully on SIGTERM
+#[cfg(unix)]
+#[test]
+fn server_cli_sigterm() {
+    let temp_dir = TempDir::new().unwrap();
+    let stderr_path = temp_dir.path().join("stderr");
+    let mut cmd = Command::cargo_bin("blaze-server").unwrap();
+    let mut child = cmd
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4007"])
+        .current_dir(&temp_dir)
+        .stderr(File::create(&stderr_path).unwrap())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::new("kill")
+        .args(["-TERM", &child.id().to_string()])
+        .assert()
+        .success();
+    assert!(child.wait().expect("unable to wait for server").success());
+
+    let content = fs::read_to_string(&stderr_path).expect("unable to read from stderr file");
+    assert!(content.contains("Server stopped"));
+}
+
+// `blaze-server` should refuse a Unix socket another server listens on or a file which is not a
+// socket, and remove its own socket when it stops
+#[cfg(unix)]
+#[test]
+fn server_cli_unix_socket() {
+    let temp_dir = TempDir::new().unwrap();
+    let socket = temp_dir.path().join("blaze.sock");
+    let addr = format!("unix:{}", socket.display());
+    let mut child = Command::cargo_bin("blaze-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", &addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    assert!(socket.exists());
+
+    let other_dir = TempDir::new().unwrap();
+    Command::cargo_bin("blaze-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", &addr])
+        .current_dir(&other_dir)
+        .assert()
+        .failure();
+
+    Command::new("kill")
+        .args(["-TERM", &child.id().to_string()])
+        .assert()
+        .success();
+    assert!(child.wait().expect("unable to wait for server").success());
+    assert!(!socket.exists());
+
+    // a file at the path which is not a socket is left alone
+    let victim = temp_dir.path().join("victim.txt");
+    fs::write(&victim, "data").unwrap();
+    Command::cargo_bin("blaze-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr"])
+        .arg(format!("unix:{}", victim.display()))
+        .current_dir(&other_dir)
+        .assert()
+        .failure();
+    assert_eq!(fs::read_to_string(&victim).unwrap(), "data");
+}
+
+// `blaze-server --tls-cert/--tls-key/--tls-client-ca` should only serve clients which connect
+// with `--tls-ca` and present a client certificate issued by the client CA
+#[test]
+fn cli_tls() {
+    let addr = "127.0.0.1:4008";
+    let temp_dir = TempDir::new().unwrap();
+    common::generate_certs(temp_dir.path(), "ca", &["server"]);
+    common::generate_certs(temp_dir.path(), "client-ca", &["client"]);
+    let mut child = Command::cargo_bin("blaze-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", addr])
+        .args(["--tls-cert", "server.pem", "--tls-key", "server.key"])
+        .args(["--tls-client-ca", "client-ca.pem"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let tls_args = [
+        "--tls-ca",
+        "ca.pem",
+        "--tls-cert",
+        "client.pem",
+        "--tls-key",
+        "client.key",
+    ];
+    Command::cargo_bin("blaze-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr])
+        .args(tls_args)
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::cargo_bin("blaze-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr])
+        .args(tls_args)
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+    Command::cargo_bin("blaze-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr, "--tls-ca", "ca.pem"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+    Command::cargo_bin("blaze-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("unable to wait for server");
+}
+
+// Users added with `blaze-admin set-user` should be able to access their keys through
+// `blaze-server --acl` with `blaze-client --user`, and nobody else. Passwords are read from a
+// prompt, a file or $BLAZE_PASSWORD, never from an argument
+#[test]
+fn cli_access_control() {
+    let addr = "127.0.0.1:4009";
+    let temp_dir = TempDir::new().unwrap();
+    assert_cmd::Command::cargo_bin("blaze-admin")
+        .unwrap()
+        .args(["set-user", "alice", "--acl", "acl.json"])
+        .args(["--read", "app/", "--write", "app/"])
+        .env_remove("BLAZE_PASSWORD")
+        .write_stdin("alice-secret\n")
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stderr(contains("Password: "));
+    fs::write(temp_dir.path().join("bob.password"), "x\n").unwrap();
+    Command::cargo_bin("blaze-admin")
+        .unwrap()
+        .args(["set-user", "bob", "--acl", "acl.json"])
+        .args([
+            "--password-file",
+            "bob.password",
+            "--read",
+            "",
+            "--write",
+            "",
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    assert_cmd::Command::cargo_bin("blaze-admin")
+        .unwrap()
+        .args(["set-user", "carol", "--acl", "acl.json"])
+        .env_remove("BLAZE_PASSWORD")
+        .write_stdin("")
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("no password on standard input"));
+    Command::cargo_bin("blaze-admin")
+        .unwrap()
+        .args(["set-user", "carol", "--acl", "acl.json", "--password", "x"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+    Command::cargo_bin("blaze-admin")
+        .unwrap()
+        .args(["remove-user", "bob", "--acl", "acl.json"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    let acl = fs::read_to_string(temp_dir.path().join("acl.json")).unwrap();
+    assert!(acl.contains("alice") && !acl.contains("bob") && !acl.contains("alice-secret"));
+
+    let mut child = Command::cargo_bin("blaze-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", addr, "--acl", "acl.json"])
+        .current_dir(&temp_dir)
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let alice = ["--user", "alice"];
+    Command::cargo_bin("blaze-client")
+        .unwrap()
+        .args(["set", "app/key1", "value1", "--addr", addr])
+        .args(alice)
+        .env("BLAZE_PASSWORD", "alice-secret")
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::cargo_bin("blaze-client")
+        .unwrap()
+        .args(["get", "app/key1", "--addr", addr])
+        .args(alice)
+        .env("BLAZE_PASSWORD", "alice-secret")
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+    Command::cargo_bin("blaze-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr])
+        .args(alice)
+        .env("BLAZE_PASSWORD", "alice-secret")
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("PermissionDenied"));
+    Command::cargo_bin("blaze-client")
+        .unwrap()
+        .args(["get", "app/key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("PermissionDenied"));
+    Command::cargo_bin("blaze-client")
+        .unwrap()
+        .args(["get", "app/key1", "--addr", addr])
+        .args(["--user", "bob", "--password-file", "bob.password"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("PermissionDenied"));
+
+    child.kill().expect("server exited before killed");
+    let output = child.wait_with_output().expect("unable to wait for server");
+    // the server is not started with --tls-cert
+    assert!(String::from_utf8_lossy(&output.stderr).contains("cleartext"));
+}
+
+#[test]
+fn server_cli_invalid_sync() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut cmd = Command::cargo_bin("blaze-server").unwrap();
+    cmd.args(["--sync", "every-n:0", "--addr", "127.0.0.1:4006"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    let mut cmd = Command::cargo_bin("blaze-server").unwrap();
+    cmd.args(["--sync", "sometimes", "--addr", "127.0.0.1:4006"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
 #[test]
 fn cli_wrong_engine() {
     // sled first, kvs second
@@ -204,15 +467,16 @@ fn cli_wrong_engine() {
         let temp_dir = TempDir::new().unwrap();
         let mut cmd = Command::cargo_bin("blaze-server").unwrap();
         let mut child = cmd
-            .args(&["--engine", "sled", "--addr", "127.0.0.1:4002"])
+            .args(["--engine", "sled", "--addr", "127.0.0.1:4002"])
             .current_dir(&temp_dir)
             .spawn()
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().expect("unable to wait for server");
 
         let mut cmd = Command::cargo_bin("blaze-server").unwrap();
-        cmd.args(&["--engine", "kvs", "--addr", "127.0.0.1:4003"])
+        cmd.args(["--engine", "kvs", "--addr", "127.0.0.1:4003"])
             .current_dir(&temp_dir)
             .assert()
             .failure();
@@ -223,39 +487,114 @@ fn cli_wrong_engine() {
         let temp_dir = TempDir::new().unwrap();
         let mut cmd = Command::cargo_bin("blaze-server").unwrap();
         let mut child = cmd
-            .args(&["--engine", "kvs", "--addr", "127.0.0.1:4002"])
+            .args(["--engine", "kvs", "--addr", "127.0.0.1:4002"])
             .current_dir(&temp_dir)
             .spawn()
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().expect("unable to wait for server");
 
         let mut cmd = Command::cargo_bin("blaze-server").unwrap();
-        cmd.args(&["--engine", "sled", "--addr", "127.0.0.1:4003"])
+        cmd.args(["--engine", "sled", "--addr", "127.0.0.1:4003"])
             .current_dir(&temp_dir)
             .assert()
             .failure();
     }
 }
 
+// `blaze-admin migrate` should copy every key with its ttl to the other engine and retire the
+// old store, in both directions.
+#[test]
+fn admin_migrate() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path().join("kvs")).unwrap();
+    for key_id in 0..3000 {
+        store
+            .set(format!("key{}", key_id), format!("value{}", key_id))
+            .unwrap();
+    }
+    store.remove("key0".to_owned()).unwrap();
+    let hour = Duration::from_secs(3600);
+    store
+        .set_with_ttl(b"session".to_vec(), b"token".to_vec(), hour)
+        .unwrap();
+    store
+        .set_with_ttl(
+            b"expired".to_vec(),
+            b"token".to_vec(),
+            Duration::from_millis(1),
+        )
+        .unwrap();
+    drop(store);
+    thread::sleep(Duration::from_millis(10));
+
+    let mut cmd = Command::cargo_bin("blaze-admin").unwrap();
+    cmd.args(["migrate", "--from", "kvs", "--to", "sled"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    assert!(matches!(
+        KvStore::open(temp_dir.path().join("kvs")),
+        Err(KVStoreError::Migrated(_))
+    ));
+    let store = SledKvsEngine::open(temp_dir.path().join("sled")).unwrap();
+    assert_eq!(store.get("key0".to_owned()).unwrap(), None);
+    for key_id in 1..3000 {
+        assert_eq!(
+            store.get(format!("key{}", key_id)).unwrap(),
+            Some(format!("value{}", key_id))
+        );
+    }
+    // keys with a ttl keep the time they have left
+    let session = store.scan_with_ttl(b"session", None, Some(1)).unwrap();
+    assert_eq!(session[0].0, b"session");
+    assert!(session[0]
+        .2
+        .is_some_and(|ttl| ttl <= hour && ttl > hour / 2));
+    assert_eq!(store.get("expired".to_owned()).unwrap(), None);
+    drop(store);
+
+    // the new store is not overwritten
+    let mut cmd = Command::cargo_bin("blaze-admin").unwrap();
+    cmd.args(["migrate", "--from", "kvs", "--to", "sled"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    fs::remove_dir_all(temp_dir.path().join("kvs")).unwrap();
+    let mut cmd = Command::cargo_bin("blaze-admin").unwrap();
+    cmd.args(["migrate", "--from", "sled", "--to", "kvs"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    let store = KvStore::open(temp_dir.path().join("kvs")).unwrap();
+    assert_eq!(store.scan(b"", None, None).unwrap().len(), 3000);
+    let session = store.scan_with_ttl(b"session", None, Some(1)).unwrap();
+    assert!(session[0]
+        .2
+        .is_some_and(|ttl| ttl <= hour && ttl > hour / 2));
+}
+
 fn cli_access_server(engine: &str, addr: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let temp_dir = TempDir::new().unwrap();
     let mut server = Command::cargo_bin("blaze-server").unwrap();
     let mut child = server
-        .args(&["--engine", engine, "--addr", addr])
+        .args(["--engine", engine, "--addr", addr])
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().expect("unable to wait for server");
     });
     thread::sleep(Duration::from_secs(1));
 
     Command::cargo_bin("blaze-client")
         .unwrap()
-        .args(&["set", "key1", "value1", "--addr", addr])
+        .args(["set", "key1", "value1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -263,7 +602,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("blaze-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -271,7 +610,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("blaze-client")
         .unwrap()
-        .args(&["set", "key1", "value2", "--addr", addr])
+        .args(["set", "key1", "value2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -279,7 +618,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("blaze-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -287,7 +626,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("blaze-client")
         .unwrap()
-        .args(&["get", "key2", "--addr", addr])
+        .args(["get", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -295,7 +634,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("blaze-client")
         .unwrap()
-        .args(&["rm", "key2", "--addr", addr])
+        .args(["rm", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .failure()
@@ -303,7 +642,87 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("blaze-client")
         .unwrap()
-        .args(&["set", "key2", "value3", "--addr", addr])
+        .args(["set", "key2", "value3", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("blaze-client")
+        .unwrap()
+        .args(["rm", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("blaze-client")
+        .unwrap()
+        .args(["set", "key3", "value4", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("blaze-client")
+        .unwrap()
+        .args(["cas", "key4", "value5", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("blaze-client")
+        .unwrap()
+        .args(["cas", "key4", "value6", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("current value: value5"));
+
+    Command::cargo_bin("blaze-client")
+        .unwrap()
+        .args(["cas", "key4", "--expected", "value5", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("blaze-client")
+        .unwrap()
+        .args(["cas", "key4", "--expected", "value5", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("key not found"));
+
+    Command::cargo_bin("blaze-client")
+        .unwrap()
+        .args(["scan", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("key2\tvalue3\nkey3\tvalue4\n");
+
+    Command::cargo_bin("blaze-client")
+        .unwrap()
+        .args(["scan", "key3", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("key3\tvalue4\n");
+
+    Command::cargo_bin("blaze-client")
+        .unwrap()
+        .args(["scan", "--prefix", "key", "--limit", "1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("key2\tvalue3\n");
+
+    Command::cargo_bin("blaze-client")
+        .unwrap()
+        .args(["set", "key5", "value7", "--ttl", "1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -311,12 +730,39 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("blaze-client")
         .unwrap()
-        .args(&["rm", "key1", "--addr", addr])
+        .args(["get", "key5", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value7\n");
+
+    Command::cargo_bin("blaze-client")
+        .unwrap()
+        .args(["compact", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
         .stdout(is_empty());
 
+    thread::sleep(Duration::from_millis(1100));
+    Command::cargo_bin("blaze-client")
+        .unwrap()
+        .args(["get", "key5", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("Key not found"));
+
+    // started without --backup-dir
+    Command::cargo_bin("blaze-client")
+        .unwrap()
+        .args(["backup", "snapshot", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("backups are not enabled"));
+    assert!(!temp_dir.path().join("snapshot").exists());
+
     sender.send(()).unwrap();
     handle.join().unwrap();
 
@@ -324,32 +770,86 @@ fn cli_access_server(engine: &str, addr: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let mut server = Command::cargo_bin("blaze-server").unwrap();
     let mut child = server
-        .args(&["--engine", engine, "--addr", addr])
+        .args([
+            "--engine",
+            engine,
+            "--addr",
+            addr,
+            "--backup-dir",
+            "backups",
+        ])
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().expect("unable to wait for server");
     });
     thread::sleep(Duration::from_secs(1));
 
     Command::cargo_bin("blaze-client")
         .unwrap()
-        .args(&["get", "key2", "--addr", addr])
+        .args(["get", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
         .stdout(contains("value3"));
     Command::cargo_bin("blaze-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
         .stdout(contains("Key not found"));
+
+    Command::cargo_bin("blaze-client")
+        .unwrap()
+        .args(["backup", "snapshot", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    for dest in ["../escaped", "/tmp/escaped", ""] {
+        Command::cargo_bin("blaze-client")
+            .unwrap()
+            .args(["backup", dest, "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .failure()
+            .stderr(contains("not a directory name inside the backup directory"));
+    }
     sender.send(()).unwrap();
     handle.join().unwrap();
+
+    // Restore the backup as the store of another directory
+    let restore_dir = TempDir::new().unwrap();
+    Command::cargo_bin("blaze-admin")
+        .unwrap()
+        .arg("restore")
+        .arg(temp_dir.path().join("backups").join("snapshot"))
+        .current_dir(&restore_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("blaze-admin")
+        .unwrap()
+        .arg("restore")
+        .arg(temp_dir.path().join("backups").join("snapshot"))
+        .current_dir(&restore_dir)
+        .assert()
+        .failure();
+    let path = restore_dir.path().join(engine);
+    let pairs = match engine {
+        "kvs" => KvStore::open(path).unwrap().scan(b"", None, None),
+        _ => SledKvsEngine::open(path).unwrap().scan(b"", None, None),
+    };
+    assert_eq!(
+        pairs.unwrap(),
+        vec![
+            (b"key2".to_vec(), b"value3".to_vec()),
+            (b"key3".to_vec(), b"value4".to_vec())
+        ]
+    );
 }
 
 #[test]
@@ -361,3 +861,11 @@ fn cli_access_server_kvs_engine() {
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+#[cfg(unix)]
+#[test]
+fn cli_access_server_unix_socket() {
+    let socket_dir = TempDir::new().unwrap();
+    let addr = format!("unix:{}", socket_dir.path().join("blaze.sock").display());
+    cli_access_server("kvs", &addr);
+}
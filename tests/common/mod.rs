@@ -0,0 +1,22 @@
+use rcgen::{BasicConstraints, CertificateParams, IsCa, KeyPair};
+use std::fs;
+use std::path::Path;
+
+/// Write a self-signed CA to `dir/<ca>.pem` and, for each of `names`, a certificate for
+/// localhost and 127.0.0.1 issued by it to `dir/<name>.pem` with its key in `dir/<name>.key`.
+pub fn generate_certs(dir: &Path, ca: &str, names: &[&str]) {
+    let ca_key = KeyPair::generate().unwrap();
+    let mut ca_params = CertificateParams::new(Vec::new()).unwrap();
+    ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    let ca_cert = ca_params.self_signed(&ca_key).unwrap();
+    fs::write(dir.join(format!("{}.pem", ca)), ca_cert.pem()).unwrap();
+
+    for name in names {
+        let key = KeyPair::generate().unwrap();
+        let params =
+            CertificateParams::new(vec!["localhost".to_owned(), "127.0.0.1".to_owned()]).unwrap();
+        let cert = params.signed_by(&key, &ca_cert, &ca_key).unwrap();
+        fs::write(dir.join(format!("{}.pem", name)), cert.pem()).unwrap();
+        fs::write(dir.join(format!("{}.key", name)), key.serialize_pem()).unwrap();
+    }
+}
@@ -1,9 +1,27 @@
-use blaze_turbo::{KvStore, KvsEngine, Result};
+use blaze_turbo::{
+    EngineType, KVStoreError, KvStore, KvStoreOptions, KvsEngine, Manifest, Result, SledKvsEngine,
+    SyncPolicy, WriteBatch,
+};
+use std::fs::{self, OpenOptions};
+use std::path::Path;
 use std::sync::{Arc, Barrier};
 use std::thread;
+use std::time::Duration;
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
+/// Open sled at `path`, waiting for a store dropped just before to release its lock, which
+/// sled does from a background thread.
+fn open_sled(path: &Path) -> Result<SledKvsEngine> {
+    for _ in 0..100 {
+        match SledKvsEngine::open(path) {
+            Err(KVStoreError::Sled(_)) => thread::sleep(Duration::from_millis(10)),
+            result => return result,
+        }
+    }
+    SledKvsEngine::open(path)
+}
+
 // Should get previously stored value
 #[test]
 fn get_stored_value() -> Result<()> {
@@ -210,3 +228,916 @@ fn concurrent_get() -> Result<()> {
 
     Ok(())
 }
+
+// Keys and values are arbitrary bytes
+#[test]
+fn binary_key_and_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    binary_key_and_value_with(|| KvStore::open(temp_dir.path()))
+}
+
+// Keys and values are arbitrary bytes in sled too
+#[test]
+fn sled_binary_key_and_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    binary_key_and_value_with(|| open_sled(temp_dir.path()))
+}
+
+fn binary_key_and_value_with<E: KvsEngine>(open: impl Fn() -> Result<E>) -> Result<()> {
+    let store = open()?;
+
+    let key = vec![0xff, 0x00, 0xfe];
+    let value: Vec<u8> = (0..=255).collect();
+    store.set_bytes(key.clone(), value.clone())?;
+    store.set("text".to_owned(), "value".to_owned())?;
+    assert_eq!(store.get_bytes(&key)?, Some(value.clone()));
+    assert_eq!(store.get_bytes(b"text")?, Some(b"value".to_vec()));
+
+    // the string API rejects values which are not valid UTF-8
+    store.set_bytes(b"invalid".to_vec(), vec![0xff])?;
+    assert!(store.get("invalid".to_owned()).is_err());
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = open()?;
+    assert_eq!(store.get_bytes(&key)?, Some(value));
+    assert_eq!(store.get("text".to_owned())?, Some("value".to_owned()));
+    store.remove_bytes(&key)?;
+    assert_eq!(store.get_bytes(&key)?, None);
+
+    Ok(())
+}
+
+// Scans return live pairs in key order
+#[test]
+fn scan_in_key_order() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    for i in (0..20).rev() {
+        store.set(format!("key{:02}", i), format!("value{}", i))?;
+    }
+    store.set("other".to_owned(), "value".to_owned())?;
+    store.remove("key05".to_owned())?;
+
+    let pair = |i: usize| {
+        (
+            format!("key{:02}", i).into_bytes(),
+            format!("value{}", i).into_bytes(),
+        )
+    };
+
+    assert_eq!(
+        store.scan(b"key03", Some(b"key08"), None)?,
+        vec![pair(3), pair(4), pair(6), pair(7)]
+    );
+    assert_eq!(store.scan(b"key18", None, None)?.len(), 3);
+    assert_eq!(store.scan(b"", None, Some(2))?, vec![pair(0), pair(1)]);
+    assert_eq!(store.scan(b"key08", Some(b"key03"), None)?, vec![]);
+
+    let prefixed = store.scan_prefix(b"key1")?;
+    assert_eq!(prefixed, (10..20).map(pair).collect::<Vec<_>>());
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.scan_prefix(b"key0")?.len(), 9);
+    assert_eq!(store.scan_prefix(b"")?.len(), 20);
+
+    // paging past removed and expired keys, over more keys than are taken at once
+    for i in 0..3000 {
+        let key = format!("page{:04}", i).into_bytes();
+        if i % 3 == 0 {
+            store.set_with_ttl(key, b"value".to_vec(), Duration::from_millis(1))?;
+        } else {
+            store.set_bytes(key, b"value".to_vec())?;
+        }
+    }
+    for i in (1..3000).step_by(3) {
+        store.remove(format!("page{:04}", i))?;
+    }
+    thread::sleep(Duration::from_millis(10));
+    let mut paged = Vec::new();
+    let mut start = b"page".to_vec();
+    loop {
+        let page = store.scan(&start, Some(b"pagf"), Some(100))?;
+        match page.last() {
+            Some((last, _)) => start = [last.as_slice(), b"\0"].concat(),
+            None => break,
+        }
+        paged.extend(page.into_iter().map(|(key, _)| key));
+    }
+    let expected: Vec<Vec<u8>> = (2..3000)
+        .step_by(3)
+        .map(|i| format!("page{:04}", i).into_bytes())
+        .collect();
+    assert_eq!(paged, expected);
+
+    Ok(())
+}
+
+// All writes of a batch are applied together
+#[test]
+fn write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    write_batch_with(|| KvStore::open(temp_dir.path()))
+}
+
+// Batches are applied as a whole by sled too
+#[test]
+fn sled_write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    write_batch_with(|| open_sled(temp_dir.path()))
+}
+
+fn write_batch_with<E: KvsEngine>(open: impl Fn() -> Result<E>) -> Result<()> {
+    let store = open()?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    let mut batch = WriteBatch::new();
+    batch.set(b"key2".to_vec(), b"value2".to_vec());
+    batch.set(b"key3".to_vec(), b"value3".to_vec());
+    batch.remove(b"key1".to_vec());
+    batch.remove(b"missing".to_vec());
+    store.write_batch(batch)?;
+
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = open()?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// A batch can only hold sets and removals, never the header of another batch
+#[test]
+fn nested_batch_header() -> Result<()> {
+    assert!(serde_json::from_str::<WriteBatch>(r#"[{"BATCH":3}]"#).is_err());
+    assert!(serde_json::from_str::<WriteBatch>(r#"[{"RM":"a"},{"BATCH":1}]"#).is_err());
+    let batch: WriteBatch = serde_json::from_str(r#"[{"SET":["a","1"]},{"RM":"b"}]"#)?;
+    assert_eq!(batch.len(), 2);
+    Ok(())
+}
+
+// A batch cut short by a crash is ignored as a whole and later writes still work
+#[test]
+fn torn_write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    let mut batch = WriteBatch::new();
+    batch.set(b"key2".to_vec(), b"value2".to_vec());
+    batch.remove(b"key1".to_vec());
+    store.write_batch(batch)?;
+    drop(store);
+
+    // cut the last command of the batch in half
+    let data_file = temp_dir.path().join("data_0.txt");
+    let length = fs::metadata(&data_file)?.len();
+    OpenOptions::new()
+        .write(true)
+        .open(&data_file)?
+        .set_len(length - 5)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// Compare and swap only writes when the current value matches
+#[test]
+fn compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    compare_and_swap_with(|| KvStore::open(temp_dir.path()))
+}
+
+// Compare and swap only writes when the current value matches in sled too
+#[test]
+fn sled_compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    compare_and_swap_with(|| open_sled(temp_dir.path()))
+}
+
+fn compare_and_swap_with<E: KvsEngine>(open: impl Fn() -> Result<E>) -> Result<()> {
+    let store = open()?;
+
+    store.set_if_absent(b"key1".to_vec(), b"value1".to_vec())?;
+    match store.set_if_absent(b"key1".to_vec(), b"value2".to_vec()) {
+        Err(KVStoreError::CompareAndSwapFailed(current)) => {
+            assert_eq!(current, Some(b"value1".to_vec()))
+        }
+        result => panic!("unexpected result {:?}", result),
+    }
+
+    match store.compare_and_swap(b"key1".to_vec(), Some(b"other".to_vec()), None) {
+        Err(KVStoreError::CompareAndSwapFailed(current)) => {
+            assert_eq!(current, Some(b"value1".to_vec()))
+        }
+        result => panic!("unexpected result {:?}", result),
+    }
+    store.compare_and_swap(
+        b"key1".to_vec(),
+        Some(b"value1".to_vec()),
+        Some(b"value2".to_vec()),
+    )?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+
+    store.compare_and_swap(b"key1".to_vec(), Some(b"value2".to_vec()), None)?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    match store.compare_and_swap(b"key1".to_vec(), Some(b"value2".to_vec()), None) {
+        Err(KVStoreError::CompareAndSwapFailed(current)) => assert_eq!(current, None),
+        result => panic!("unexpected result {:?}", result),
+    }
+
+    // concurrent increments never lose an update
+    store.set("counter".to_owned(), "0".to_owned())?;
+    let mut handles = Vec::new();
+    for _ in 0..8 {
+        let store = store.clone();
+        handles.push(thread::spawn(move || {
+            for _ in 0..50 {
+                loop {
+                    let current = store.get_bytes(b"counter").unwrap().unwrap();
+                    let next: u64 = String::from_utf8(current.clone()).unwrap().parse().unwrap();
+                    let next = (next + 1).to_string().into_bytes();
+                    match store.compare_and_swap(b"counter".to_vec(), Some(current), Some(next)) {
+                        Ok(()) => break,
+                        Err(KVStoreError::CompareAndSwapFailed(_)) => continue,
+                        Err(err) => panic!("{}", err),
+                    }
+                }
+            }
+        }));
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert_eq!(store.get("counter".to_owned())?, Some("400".to_owned()));
+
+    Ok(())
+}
+
+// Keys set with a ttl should disappear once it elapses, also after reopening the store.
+#[test]
+fn expire_keys_with_ttl() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    expire_keys_with_ttl_with(|| KvStore::open(temp_dir.path()))
+}
+
+// Keys set with a ttl expire in sled too, also after reopening it
+#[test]
+fn sled_expire_keys_with_ttl() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    expire_keys_with_ttl_with(|| open_sled(temp_dir.path()))
+}
+
+fn expire_keys_with_ttl_with<E: KvsEngine>(open: impl Fn() -> Result<E>) -> Result<()> {
+    let store = open()?;
+
+    store.set_with_ttl(
+        b"key1".to_vec(),
+        b"value1".to_vec(),
+        Duration::from_millis(100),
+    )?;
+    store.set_with_ttl(
+        b"key2".to_vec(),
+        b"value2".to_vec(),
+        Duration::from_secs(3600),
+    )?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(
+        store.scan(b"", None, None)?,
+        vec![
+            (b"key2".to_vec(), b"value2".to_vec()),
+            (b"key3".to_vec(), b"value3".to_vec()),
+        ]
+    );
+    match store.remove("key1".to_owned()) {
+        Err(KVStoreError::KeyNotFound) => {}
+        result => panic!("unexpected result {:?}", result),
+    }
+    store.set_if_absent(b"key1".to_vec(), b"value4".to_vec())?;
+    store.set_with_ttl(
+        b"key5".to_vec(),
+        b"value5".to_vec(),
+        Duration::from_millis(1),
+    )?;
+    thread::sleep(Duration::from_millis(10));
+
+    drop(store);
+    let store = open()?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value4".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key5".to_owned())?, None);
+
+    Ok(())
+}
+
+// A record cut short by a crash at the end of the active file should be dropped on open,
+// while damage in the middle of a file should fail with its location.
+#[test]
+fn torn_and_corrupted_records() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let data_file = temp_dir.path().join("data_0.txt");
+    let first_length = fs::metadata(&data_file)?.len();
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let length = fs::metadata(&data_file)?.len();
+    OpenOptions::new()
+        .write(true)
+        .open(&data_file)?
+        .set_len(length - 3)?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(fs::metadata(&data_file)?.len(), first_length);
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    // flip a byte in the payload of the first record
+    let mut data = fs::read(&data_file)?;
+    data[10] ^= 0x01;
+    fs::write(&data_file, &data)?;
+    match KvStore::open(temp_dir.path()) {
+        Err(KVStoreError::Corruption {
+            file_number,
+            offset,
+        }) => assert_eq!((file_number, offset), (0, 0)),
+        result => panic!("unexpected result {:?}", result.err()),
+    }
+
+    // a damaged length in the middle of the active file is not mistaken for a torn record
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let data_file = temp_dir.path().join("data_0.txt");
+    let first_length = fs::metadata(&data_file)?.len();
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+    let mut data = fs::read(&data_file)?;
+    data[first_length as usize + 1] ^= 0x01;
+    fs::write(&data_file, &data)?;
+    match KvStore::open(temp_dir.path()) {
+        Err(KVStoreError::Corruption {
+            file_number,
+            offset,
+        }) => assert_eq!((file_number, offset), (0, first_length)),
+        result => panic!("unexpected result {:?}", result.err()),
+    }
+    assert_eq!(fs::read(&data_file)?, data);
+
+    Ok(())
+}
+
+// Data files written before records had a header should be converted on open, without
+// losing any command.
+#[test]
+fn legacy_data_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(
+        temp_dir.path().join("data_0.txt"),
+        r#"{"SET":["a","1"]}{"SET":["b","2"]}{"SET":["c","3"]}"#,
+    )?;
+    fs::write(
+        temp_dir.path().join("data_1.txt"),
+        r#"{"RM":"b"}{"BATCH":2}{"SET":["c","4"]}{"SET":["d","5"]}"#,
+    )?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("a".to_owned())?, Some("1".to_owned()));
+    assert_eq!(store.get("b".to_owned())?, None);
+    assert_eq!(store.get("c".to_owned())?, Some("4".to_owned()));
+    assert_eq!(store.get("d".to_owned())?, Some("5".to_owned()));
+    store.set("e".to_owned(), "6".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("a".to_owned())?, Some("1".to_owned()));
+    assert_eq!(store.get("c".to_owned())?, Some("4".to_owned()));
+    assert_eq!(store.get("e".to_owned())?, Some("6".to_owned()));
+
+    Ok(())
+}
+
+// Every sync policy should keep acknowledged writes across a reopen.
+#[test]
+fn sync_policies() -> Result<()> {
+    for policy in ["never", "every-write", "every-n:2", "interval:10"] {
+        let policy: SyncPolicy = policy.parse()?;
+        assert_eq!(policy.to_string().parse::<SyncPolicy>()?, policy);
+
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions::new().sync_policy(policy);
+        let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+        for i in 0..5 {
+            store.set(format!("key{}", i), format!("value{}", i))?;
+        }
+        store.remove("key0".to_owned())?;
+        thread::sleep(Duration::from_millis(20));
+        drop(store);
+
+        let store = KvStore::open_with(temp_dir.path(), options)?;
+        assert_eq!(store.get("key0".to_owned())?, None);
+        for i in 1..5 {
+            assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+        }
+    }
+
+    for policy in ["always", "every-n:0", "every-n:x", "interval:"] {
+        assert!(policy.parse::<SyncPolicy>().is_err());
+    }
+
+    Ok(())
+}
+
+// Concurrent writers should all be acknowledged and recovered, with and without group commit.
+#[test]
+fn group_commit() -> Result<()> {
+    for group_commit in [false, true] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions::new()
+            .sync_policy(SyncPolicy::EveryWrite)
+            .group_commit(group_commit);
+        let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+
+        let handles: Vec<_> = (0..8)
+            .map(|t| {
+                let store = store.clone();
+                thread::spawn(move || -> Result<()> {
+                    for i in 0..50 {
+                        store.set(format!("key{}_{}", t, i), format!("value{}", i))?;
+                    }
+                    for i in 0..50 {
+                        if i % 2 == 0 {
+                            store.remove(format!("key{}_{}", t, i))?;
+                        }
+                    }
+                    // the second remove of a key must see the first one
+                    match store.remove(format!("key{}_0", t)) {
+                        Err(KVStoreError::KeyNotFound) => Ok(()),
+                        result => panic!("unexpected result {:?}", result),
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap()?;
+        }
+        drop(store);
+
+        let store = KvStore::open_with(temp_dir.path(), options)?;
+        for t in 0..8 {
+            for i in 0..50 {
+                let expected = (i % 2 == 1).then(|| format!("value{}", i));
+                assert_eq!(store.get(format!("key{}_{}", t, i))?, expected);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Reads and writes should keep working while compactions run in the background.
+#[test]
+fn background_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    // output of a compaction interrupted by a crash
+    fs::write(temp_dir.path().join("data_7.compact"), b"partial")?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(!temp_dir.path().join("data_7.compact").exists());
+
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "0".to_owned())?;
+    }
+    let reader_store = store.clone();
+    let reader = thread::spawn(move || -> Result<()> {
+        for _ in 0..200 {
+            for key_id in 0..100 {
+                let value = reader_store.get(format!("key{}", key_id))?;
+                assert!(value.is_some(), "key{} is missing", key_id);
+            }
+        }
+        Ok(())
+    });
+    let value = "x".repeat(1000);
+    for iter in 0..50 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{}{}", iter, value))?;
+        }
+    }
+    reader.join().unwrap()?;
+    drop(store);
+
+    let data_files = fs::read_dir(temp_dir.path())?.count();
+    assert!(
+        data_files < 10,
+        "{} files left after compaction",
+        data_files
+    );
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("49{}", value))
+        );
+    }
+
+    Ok(())
+}
+
+// Compaction should wait for both triggers, and run on demand regardless of them.
+#[test]
+fn compaction_triggers() -> Result<()> {
+    let data_files = |temp_dir: &TempDir| -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(temp_dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .filter(|name| name.starts_with("data_"))
+            .collect();
+        names.sort();
+        names
+    };
+
+    // live data always makes up part of the files, so a ratio of 1.0 is never reached
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .compaction_threshold(0)
+        .garbage_ratio(1.0);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for iter in 0..100 {
+        store.set("key".to_owned(), format!("{}", iter))?;
+    }
+    assert_eq!(data_files(&temp_dir), vec!["data_0.txt"]);
+
+    // compacting on demand leaves the compacted file and a fresh active file
+    store.compact()?;
+    assert_eq!(
+        data_files(&temp_dir),
+        vec!["data_1.hint", "data_1.txt", "data_2.txt"]
+    );
+    assert_eq!(store.get("key".to_owned())?, Some("99".to_owned()));
+    store.set("key".to_owned(), "100".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key".to_owned())?, Some("100".to_owned()));
+    drop(store);
+
+    // a low threshold and ratio compact as soon as half of the files are garbage
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .compaction_threshold(0)
+        .garbage_ratio(0.5);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for iter in 0..100 {
+        store.set("key".to_owned(), format!("{}", iter))?;
+    }
+    store.compact()?;
+    assert!(!data_files(&temp_dir).contains(&"data_0.txt".to_owned()));
+    assert_eq!(store.get("key".to_owned())?, Some("99".to_owned()));
+
+    Ok(())
+}
+
+// Compaction should leave a hint file which recovery uses instead of reading the values,
+// falling back to a scan if the hint is damaged or missing.
+#[test]
+fn hint_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.remove("key0".to_owned())?;
+    store.set_with_ttl(
+        b"key1".to_vec(),
+        b"value1".to_vec(),
+        Duration::from_secs(3600),
+    )?;
+    store.compact()?;
+    drop(store);
+
+    let data_file = temp_dir.path().join("data_1.txt");
+    let hint_file = temp_dir.path().join("data_1.hint");
+    assert!(hint_file.exists());
+    let check = || -> Result<()> {
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("key0".to_owned())?, None);
+        for key_id in 1..10 {
+            assert_eq!(
+                store.get(format!("key{}", key_id))?,
+                Some(format!("value{}", key_id))
+            );
+        }
+        Ok(())
+    };
+    check()?;
+
+    // a damaged hint is ignored
+    let hint = fs::read(&hint_file)?;
+    let mut damaged = hint.clone();
+    damaged[10] ^= 0x01;
+    fs::write(&hint_file, &damaged)?;
+    check()?;
+
+    // with the hint the values are not read while recovering
+    fs::write(&hint_file, &hint)?;
+    let mut data = fs::read(&data_file)?;
+    let last = data.len() - 3;
+    data[last] ^= 0x01;
+    fs::write(&data_file, &data)?;
+    KvStore::open(temp_dir.path())?;
+    fs::remove_file(&hint_file)?;
+    assert!(KvStore::open(temp_dir.path()).is_err());
+
+    Ok(())
+}
+
+// The active file should be sealed once it reaches the maximum size, and compacting only some
+// of the sealed files must keep their removals of keys set in the files left alone.
+#[test]
+fn file_rotation() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let data_file = |number: u64| temp_dir.path().join(format!("data_{}.txt", number));
+    let options = KvStoreOptions::new()
+        .max_file_size(200)
+        .compaction_threshold(u64::MAX);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    store.set("key0".to_owned(), "value0".to_owned())?;
+    for key_id in 1..20 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.remove("key0".to_owned())?;
+    let last = (0..)
+        .take_while(|&number| data_file(number).exists())
+        .last();
+    let last = last.expect("no data file written");
+    assert!(last >= 3);
+    for number in 0..last {
+        assert!(fs::metadata(data_file(number))?.len() >= 200);
+    }
+
+    // files which do not exist are ignored
+    store.compact_files(&[last + 100])?;
+    assert!(data_file(last).exists());
+
+    store.compact_files(&[last])?;
+    assert!(data_file(0).exists());
+    assert!(!data_file(last).exists());
+    assert!(data_file(last + 1).exists());
+    assert_eq!(store.get("key0".to_owned())?, None);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    for key_id in 1..20 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("value{}", key_id))
+        );
+    }
+
+    // without the hint the removal is recovered from the compacted file itself
+    drop(store);
+    fs::remove_file(temp_dir.path().join(format!("data_{}.hint", last + 1)))?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+
+    Ok(())
+}
+
+// Live and dead bytes should be tracked per file, survive a reopen, and let automatic
+// compactions merge only the files which are mostly dead.
+#[test]
+fn file_stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = || {
+        KvStoreOptions::new()
+            .max_file_size(300)
+            .compaction_threshold(u64::MAX)
+    };
+    let value = |key_id| format!("value{}", key_id).repeat(20);
+    let store = KvStore::open_with(temp_dir.path(), options())?;
+    for key_id in 0..5 {
+        store.set(format!("key{}", key_id), value(key_id))?;
+    }
+    for iter in 0..50 {
+        store.set("hot".to_owned(), format!("{}", iter))?;
+    }
+    let stats = store.file_stats();
+    assert!(stats.len() > 3);
+    assert_eq!(stats[&0].dead_bytes, 0);
+    assert_eq!(stats[&0].dead_ratio(), 0.0);
+    assert_eq!(
+        stats[&0].total_bytes,
+        fs::metadata(temp_dir.path().join("data_0.txt"))?.len()
+    );
+    let last = *stats.keys().last().unwrap();
+    assert!(stats[&(last - 1)].dead_ratio() > 0.9);
+    assert_eq!(stats[&(last - 1)].live_bytes(), 0);
+    drop(store);
+
+    let store = KvStore::open_with(temp_dir.path(), options())?;
+    assert_eq!(store.file_stats(), stats);
+    drop(store);
+
+    // only the mostly dead files are merged
+    let options = options().compaction_threshold(0).merge_ratio(0.5);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    store.set("hot".to_owned(), "50".to_owned())?;
+    drop(store);
+    assert!(temp_dir.path().join("data_0.txt").exists());
+    let merged = temp_dir.path().join(format!("data_{}.txt", last - 1));
+    assert!(!merged.exists());
+
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..5 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(value(key_id)));
+    }
+    assert_eq!(store.get("hot".to_owned())?, Some("50".to_owned()));
+    let stats = store.file_stats();
+    assert!(stats.values().all(|stats| stats.dead_ratio() < 0.5));
+
+    Ok(())
+}
+
+// A store directory should only be opened by one store at a time, until it is dropped.
+#[test]
+fn lock_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    match KvStore::open(temp_dir.path()) {
+        Err(KVStoreError::StoreLocked(path)) => assert_eq!(path, temp_dir.path()),
+        _ => panic!("the store was opened twice"),
+    }
+
+    // clones share the lock
+    let clone = store.clone();
+    drop(store);
+    assert!(KvStore::open(temp_dir.path()).is_err());
+    drop(clone);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// A store should record its engine, format version and options in a manifest on creation,
+// refuse to open stores of another engine or of a newer format, and upgrade older ones.
+#[test]
+fn manifest() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().sync_policy(SyncPolicy::EveryN(10));
+    drop(KvStore::open_with(temp_dir.path(), options)?);
+    let mut manifest = Manifest::load(temp_dir.path())?.expect("no manifest written");
+    assert_eq!(manifest.engine, EngineType::KvStore);
+    assert_eq!(manifest.format_version, 1);
+    assert_eq!(manifest.options["sync_policy"], "every-n:10");
+
+    // reopening with other options keeps the manifest
+    drop(KvStore::open(temp_dir.path())?);
+    assert_eq!(Manifest::load(temp_dir.path())?, Some(manifest.clone()));
+
+    assert!(matches!(
+        SledKvsEngine::open(temp_dir.path()),
+        Err(KVStoreError::ChangeEngineError)
+    ));
+
+    manifest.format_version = 2;
+    manifest.store(temp_dir.path())?;
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KVStoreError::UnsupportedFormatVersion(2))
+    ));
+
+    // stores created before manifests are of format version 0 and get upgraded when opened
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(
+        temp_dir.path().join("data_0.txt"),
+        r#"{"SET":["key1","value1"]}{"SET":["key2","value2"]}"#,
+    )?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    drop(store);
+    let manifest = Manifest::load(temp_dir.path())?.expect("no manifest written");
+    assert_eq!(manifest.format_version, 1);
+
+    // an interrupted upgrade is resumed, as the manifest still records version 0
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(
+        temp_dir.path().join("data_0.txt"),
+        r#"{"SET":["key1","value1"]}"#,
+    )?;
+    Manifest {
+        format_version: 0,
+        ..manifest.clone()
+    }
+    .store(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(store);
+
+    // data of the old format in a store of the current one is damage, not a torn record
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    drop(KvStore::open(temp_dir.path())?);
+    let data_file = temp_dir.path().join("data_0.txt");
+    fs::write(&data_file, r#"{"SET":["key1","value1"]}"#)?;
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KVStoreError::Corruption {
+            file_number: 0,
+            offset: 0
+        })
+    ));
+    assert_eq!(fs::read(&data_file)?, br#"{"SET":["key1","value1"]}"#);
+
+    Ok(())
+}
+
+// A snapshot should hold the data as of the time it was taken, open as a store of its own
+// and not be touched by later writes or compactions of either store.
+#[test]
+fn snapshot() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let snapshot_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path().join("store"))?;
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.remove("key0".to_owned())?;
+    store.snapshot(snapshot_dir.path())?;
+    assert!(store.snapshot(snapshot_dir.path()).is_err());
+
+    store.set("key1".to_owned(), "changed".to_owned())?;
+    store.compact()?;
+
+    let check = |store: &KvStore| -> Result<()> {
+        assert_eq!(store.get("key0".to_owned())?, None);
+        for key_id in 1..10 {
+            assert_eq!(
+                store.get(format!("key{}", key_id))?,
+                Some(format!("value{}", key_id))
+            );
+        }
+        Ok(())
+    };
+    let snapshot = KvStore::open(snapshot_dir.path())?;
+    check(&snapshot)?;
+    snapshot.set("key2".to_owned(), "changed".to_owned())?;
+    snapshot.compact()?;
+    drop(snapshot);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path().join("store"))?;
+    assert_eq!(store.get("key1".to_owned())?, Some("changed".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    // sled copies its trees into the snapshot
+    let store = SledKvsEngine::open(temp_dir.path().join("sled"))?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set_with_ttl(
+        b"key2".to_vec(),
+        b"value2".to_vec(),
+        Duration::from_millis(1),
+    )?;
+    let snapshot_dir = temp_dir.path().join("sled_snapshot");
+    store.snapshot(&snapshot_dir)?;
+    store.set("key1".to_owned(), "changed".to_owned())?;
+    drop(store);
+    thread::sleep(Duration::from_millis(10));
+    let snapshot = SledKvsEngine::open(&snapshot_dir)?;
+    assert_eq!(snapshot.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(snapshot.get("key2".to_owned())?, None);
+    assert_eq!(
+        Manifest::load(&snapshot_dir)?.map(|manifest| manifest.engine),
+        Some(EngineType::SledKvsEngine)
+    );
+
+    Ok(())
+}
@@ -0,0 +1,587 @@
+use blaze_turbo::{
+    AccessControl, Client, ClientTlsConfig, KVStoreError, KvServer, KvStore, KvsEngine,
+    Permissions, Protocol, Request, Result, ServerTlsConfig, SharedQueueThreadPool, ShutdownHandle,
+    ThreadPool, WriteBatch,
+};
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::mpsc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+mod common;
+
+fn new_server(dir: &TempDir) -> KvServer<KvStore, SharedQueueThreadPool> {
+    let engine = KvStore::open(dir.path()).unwrap();
+    let pool = SharedQueueThreadPool::new(4).unwrap();
+    KvServer::new(engine, pool)
+}
+
+fn start_server(addr: &'static str, dir: &TempDir) -> (ShutdownHandle, JoinHandle<()>) {
+    run_server(addr, new_server(dir))
+}
+
+fn run_server(
+    addr: &'static str,
+    mut server: KvServer<KvStore, SharedQueueThreadPool>,
+) -> (ShutdownHandle, JoinHandle<()>) {
+    let shutdown = server.shutdown_handle();
+    let handle = thread::spawn(move || {
+        server.serve(addr).unwrap();
+    });
+    thread::sleep(Duration::from_millis(500));
+    (shutdown, handle)
+}
+
+fn stop_server(shutdown: ShutdownHandle, handle: JoinHandle<()>) {
+    shutdown.shutdown();
+    handle.join().unwrap();
+}
+
+// One client should be able to issue many requests over the same connection
+#[test]
+fn multiple_requests_on_one_connection() -> Result<()> {
+    let addr = "127.0.0.1:4101";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (shutdown, handle) = start_server(addr, &temp_dir);
+
+    let mut client = Client::new(addr)?;
+    for i in 0..100 {
+        client.request(&Request::SET(
+            format!("key{}", i).into_bytes(),
+            format!("value{}", i).into_bytes(),
+        ))?;
+    }
+    for i in 0..100 {
+        assert_eq!(
+            client.request(&Request::GET(format!("key{}", i).into_bytes()))?,
+            Some(format!("value{}", i).into_bytes())
+        );
+    }
+    client.request(&Request::RM(b"key0".to_vec()))?;
+    assert_eq!(client.request(&Request::GET(b"key0".to_vec()))?, None);
+    assert!(client.request(&Request::RM(b"key0".to_vec())).is_err());
+
+    // the connection is still usable after an error response
+    assert_eq!(
+        client.request(&Request::GET(b"key1".to_vec()))?,
+        Some(b"value1".to_vec())
+    );
+
+    drop(client);
+    stop_server(shutdown, handle);
+    Ok(())
+}
+
+// Pipelined requests should be answered in order with one result per request
+#[test]
+fn pipelined_requests() -> Result<()> {
+    let addr = "127.0.0.1:4102";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (shutdown, handle) = start_server(addr, &temp_dir);
+
+    let mut client = Client::new(addr)?;
+    let mut pipeline = client.pipeline();
+    for i in 0..1000 {
+        pipeline = pipeline.set(format!("key{}", i), format!("value{}", i));
+    }
+    let results = pipeline.execute()?;
+    assert_eq!(results.len(), 1000);
+    assert!(results.iter().all(|result| matches!(result, Ok(None))));
+
+    let results = client
+        .pipeline()
+        .get("key1")
+        .remove("key1")
+        .get("key1")
+        .remove("key1")
+        .set("key1", "value")
+        .get("key1")
+        .execute()?;
+    assert_eq!(results.len(), 6);
+    assert_eq!(results[0].as_ref().unwrap(), &Some(b"value1".to_vec()));
+    assert_eq!(results[1].as_ref().unwrap(), &None);
+    assert_eq!(results[2].as_ref().unwrap(), &None);
+    assert!(results[3].is_err());
+    assert_eq!(results[4].as_ref().unwrap(), &None);
+    assert_eq!(results[5].as_ref().unwrap(), &Some(b"value".to_vec()));
+
+    drop(client);
+    stop_server(shutdown, handle);
+    Ok(())
+}
+
+// A pipeline whose requests and answers are both larger than the socket buffers should still
+// be answered, rather than leave both sides blocked writing
+#[test]
+fn large_pipeline() -> Result<()> {
+    let addr = "127.0.0.1:4112";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (shutdown, handle) = start_server(addr, &temp_dir);
+
+    let keys: Vec<Vec<u8>> = (0..10u8).map(|i| vec![i; 1024]).collect();
+    let value = vec![b'v'; 1024];
+    for protocol in [Protocol::Json, Protocol::Binary] {
+        let (keys, value) = (keys.clone(), value.clone());
+        // well past the largest socket buffers in either direction with binary frames
+        let count = match protocol {
+            Protocol::Json => 2_000,
+            Protocol::Binary => 12_000,
+        };
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let result = (|| -> Result<usize> {
+                let mut client = Client::connect(addr, protocol)?;
+                let mut pipeline = client.pipeline();
+                for key in &keys {
+                    pipeline = pipeline.set(key.clone(), value.clone());
+                }
+                for i in 0..count {
+                    pipeline = pipeline.get(keys[i % keys.len()].clone());
+                }
+                let results = pipeline.execute()?;
+                Ok(results
+                    .into_iter()
+                    .skip(keys.len())
+                    .filter(|result| matches!(result, Ok(Some(found)) if *found == value))
+                    .count())
+            })();
+            sender.send(result).unwrap();
+        });
+        let found = receiver
+            .recv_timeout(Duration::from_secs(60))
+            .expect("pipeline blocked");
+        assert_eq!(found?, count);
+    }
+
+    stop_server(shutdown, handle);
+    Ok(())
+}
+
+// JSON clients and binary clients should share the same server and data
+#[test]
+fn json_and_binary_protocols() -> Result<()> {
+    let addr = "127.0.0.1:4103";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (shutdown, handle) = start_server(addr, &temp_dir);
+
+    let mut json_client = Client::connect(addr, Protocol::Json)?;
+    let mut binary_client = Client::connect(addr, Protocol::Binary)?;
+
+    let results = json_client
+        .pipeline()
+        .set("key1", "value1")
+        .set("key2", "value2")
+        .get("key1")
+        .execute()?;
+    assert_eq!(results[2].as_ref().unwrap(), &Some(b"value1".to_vec()));
+    assert_eq!(
+        binary_client.request(&Request::GET(b"key2".to_vec()))?,
+        Some(b"value2".to_vec())
+    );
+    binary_client.request(&Request::SET(b"key3".to_vec(), b"value3".to_vec()))?;
+    assert_eq!(
+        json_client.request(&Request::GET(b"key3".to_vec()))?,
+        Some(b"value3".to_vec())
+    );
+
+    // a plain JSON peer without any handshake
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(br#"{"GET":"key1"}{"GET":"key4"}"#)?;
+    let expected = br#"{"Ok":"value1"}{"Ok":null}"#;
+    let mut received = vec![0; expected.len()];
+    stream.read_exact(&mut received)?;
+    assert_eq!(&received[..], &expected[..]);
+
+    drop(stream);
+    drop(json_client);
+    drop(binary_client);
+    stop_server(shutdown, handle);
+    Ok(())
+}
+
+// Keys and values which are not valid UTF-8 should survive both protocols
+#[test]
+fn binary_keys_and_values() -> Result<()> {
+    let addr = "127.0.0.1:4104";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (shutdown, handle) = start_server(addr, &temp_dir);
+
+    let key = vec![0xff, 0x00, 0xfe];
+    let value: Vec<u8> = (0..=255).collect();
+    for protocol in [Protocol::Json, Protocol::Binary] {
+        let mut client = Client::connect(addr, protocol)?;
+        client.request(&Request::SET(key.clone(), value.clone()))?;
+        assert_eq!(
+            client.request(&Request::GET(key.clone()))?,
+            Some(value.clone())
+        );
+        client.request(&Request::RM(key.clone()))?;
+        assert_eq!(client.request(&Request::GET(key.clone()))?, None);
+    }
+
+    stop_server(shutdown, handle);
+    Ok(())
+}
+
+// A batch sent over the wire is applied as a whole
+#[test]
+fn batch_request() -> Result<()> {
+    let addr = "127.0.0.1:4105";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (shutdown, handle) = start_server(addr, &temp_dir);
+
+    for protocol in [Protocol::Json, Protocol::Binary] {
+        let mut client = Client::connect(addr, protocol)?;
+        client.request(&Request::SET(b"key1".to_vec(), b"value1".to_vec()))?;
+
+        let mut batch = WriteBatch::new();
+        batch.set(b"key2".to_vec(), b"value2".to_vec());
+        batch.remove(b"key1".to_vec());
+        client.request(&Request::BATCH(batch))?;
+
+        assert_eq!(client.request(&Request::GET(b"key1".to_vec()))?, None);
+        assert_eq!(
+            client.request(&Request::GET(b"key2".to_vec()))?,
+            Some(b"value2".to_vec())
+        );
+        client.request(&Request::RM(b"key2".to_vec()))?;
+    }
+
+    stop_server(shutdown, handle);
+    Ok(())
+}
+
+// A batch header smuggled into a batch is refused, so it cannot swallow the writes after it
+// when the store is opened again
+#[test]
+fn nested_batch_refused() -> Result<()> {
+    let addr = "127.0.0.1:4111";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (shutdown, handle) = start_server(addr, &temp_dir);
+
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(br#"{"BATCH":[{"BATCH":3}]}"#)?;
+    let mut received = Vec::new();
+    stream.read_to_end(&mut received)?;
+    assert!(received.is_empty());
+
+    let mut client = Client::new(addr)?;
+    client.request(&Request::SET(b"a".to_vec(), b"1".to_vec()))?;
+    client.request(&Request::SET(b"b".to_vec(), b"2".to_vec()))?;
+    drop(client);
+    stop_server(shutdown, handle);
+
+    let (shutdown, handle) = start_server(addr, &temp_dir);
+    let mut client = Client::new(addr)?;
+    assert_eq!(
+        client.request(&Request::GET(b"a".to_vec()))?,
+        Some(b"1".to_vec())
+    );
+    assert_eq!(
+        client.request(&Request::GET(b"b".to_vec()))?,
+        Some(b"2".to_vec())
+    );
+    drop(client);
+    stop_server(shutdown, handle);
+    Ok(())
+}
+
+// Idle connections hold a pool thread each, so connections beyond the pool are refused
+// rather than left waiting, and are accepted again once one closes
+#[test]
+fn connection_limit() -> Result<()> {
+    let addr = "127.0.0.1:4113";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::open(temp_dir.path())?;
+    let server = KvServer::new(engine, SharedQueueThreadPool::new(2)?).max_connections(2);
+    let (shutdown, handle) = run_server(addr, server);
+
+    let mut first = Client::new(addr)?;
+    let mut second = Client::new(addr)?;
+    first.request(&Request::SET(b"key1".to_vec(), b"1".to_vec()))?;
+    let started = Instant::now();
+    assert!(Client::new(addr).is_err());
+    assert!(started.elapsed() < Duration::from_secs(5));
+    assert_eq!(
+        second.request(&Request::GET(b"key1".to_vec()))?,
+        Some(b"1".to_vec())
+    );
+
+    drop(first);
+    thread::sleep(Duration::from_millis(200));
+    let mut third = Client::new(addr)?;
+    assert_eq!(
+        third.request(&Request::GET(b"key1".to_vec()))?,
+        Some(b"1".to_vec())
+    );
+
+    drop(second);
+    drop(third);
+    stop_server(shutdown, handle);
+    Ok(())
+}
+
+// A shutdown should answer the requests in flight, close idle connections without waiting for
+// their timeout, stop accepting and leave every acknowledged write on disk.
+#[test]
+fn graceful_shutdown() -> Result<()> {
+    let addr = "127.0.0.1:4106";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (shutdown, handle) = start_server(addr, &temp_dir);
+
+    let mut idle_client = Client::new(addr)?;
+    idle_client.request(&Request::SET(b"key0".to_vec(), b"value0".to_vec()))?;
+
+    let writer = thread::spawn(move || {
+        let mut client = Client::new(addr).unwrap();
+        let mut acknowledged = 0;
+        for i in 1.. {
+            let request = Request::SET(format!("key{}", i).into_bytes(), b"value".to_vec());
+            match client.request(&request) {
+                Ok(_) => acknowledged = i,
+                Err(_) => break,
+            }
+        }
+        acknowledged
+    });
+    thread::sleep(Duration::from_millis(200));
+
+    let start = Instant::now();
+    stop_server(shutdown, handle);
+    assert!(start.elapsed() < Duration::from_secs(5));
+    let acknowledged = writer.join().unwrap();
+    assert!(acknowledged > 0);
+    assert!(idle_client
+        .request(&Request::GET(b"key0".to_vec()))
+        .is_err());
+    assert!(Client::new(addr).is_err());
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, Some("value0".to_owned()));
+    for i in 1..=acknowledged {
+        assert_eq!(store.get(format!("key{}", i))?, Some("value".to_owned()));
+    }
+
+    Ok(())
+}
+
+// New connections should be accepted right away, and a shutdown should wake up the listener
+// waiting for them, also when it listens on every interface.
+#[test]
+fn accept_without_delay() -> Result<()> {
+    let addr = "0.0.0.0:4110";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (shutdown, handle) = start_server(addr, &temp_dir);
+
+    let start = Instant::now();
+    for _ in 0..20 {
+        let mut client = Client::new("127.0.0.1:4110")?;
+        client.request(&Request::GET(b"key".to_vec()))?;
+    }
+    assert!(start.elapsed() < Duration::from_millis(200));
+
+    let start = Instant::now();
+    stop_server(shutdown, handle);
+    assert!(start.elapsed() < Duration::from_secs(1));
+    assert!(Client::new("127.0.0.1:4110").is_err());
+
+    Ok(())
+}
+
+// Clients which trust the server's CA should talk to it over TLS in both protocols, while
+// plaintext clients and clients trusting another CA are refused
+#[test]
+fn tls_connections() -> Result<()> {
+    let addr = "127.0.0.1:4107";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let cert_dir = TempDir::new().expect("unable to create temporary working directory");
+    common::generate_certs(cert_dir.path(), "ca", &["server"]);
+    common::generate_certs(cert_dir.path(), "other-ca", &[]);
+    let tls = ServerTlsConfig::new(
+        cert_dir.path().join("server.pem"),
+        cert_dir.path().join("server.key"),
+    )?;
+    let (shutdown, handle) = run_server(addr, new_server(&temp_dir).tls(tls));
+
+    let client_tls = ClientTlsConfig::new(cert_dir.path().join("ca.pem"))?;
+    for protocol in [Protocol::Json, Protocol::Binary] {
+        let mut client = Client::connect_tls(addr, protocol, &client_tls)?;
+        client.request(&Request::SET(b"key1".to_vec(), b"value1".to_vec()))?;
+        assert_eq!(
+            client.request(&Request::GET(b"key1".to_vec()))?,
+            Some(b"value1".to_vec())
+        );
+        client.request(&Request::RM(b"key1".to_vec()))?;
+    }
+
+    let localhost = client_tls.clone().server_name("localhost")?;
+    let mut client = Client::connect_tls(addr, Protocol::Binary, &localhost)?;
+    assert_eq!(client.request(&Request::GET(b"key1".to_vec()))?, None);
+
+    let other_tls = ClientTlsConfig::new(cert_dir.path().join("other-ca.pem"))?;
+    assert!(Client::connect_tls(addr, Protocol::Binary, &other_tls).is_err());
+    assert!(Client::new(addr)
+        .and_then(|mut client| client.request(&Request::GET(b"key1".to_vec())))
+        .is_err());
+
+    drop(client);
+    stop_server(shutdown, handle);
+    Ok(())
+}
+
+// A server requiring mutual TLS should only serve clients presenting a certificate issued by
+// its client CA
+#[test]
+fn mutual_tls() -> Result<()> {
+    let addr = "127.0.0.1:4108";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let cert_dir = TempDir::new().expect("unable to create temporary working directory");
+    common::generate_certs(cert_dir.path(), "ca", &["server"]);
+    common::generate_certs(cert_dir.path(), "client-ca", &["client"]);
+    common::generate_certs(cert_dir.path(), "other-ca", &["other"]);
+    let tls = ServerTlsConfig::with_client_auth(
+        cert_dir.path().join("server.pem"),
+        cert_dir.path().join("server.key"),
+        cert_dir.path().join("client-ca.pem"),
+    )?;
+    let (shutdown, handle) = run_server(addr, new_server(&temp_dir).tls(tls));
+
+    let client_tls = ClientTlsConfig::with_client_cert(
+        cert_dir.path().join("ca.pem"),
+        cert_dir.path().join("client.pem"),
+        cert_dir.path().join("client.key"),
+    )?;
+    let mut client = Client::connect_tls(addr, Protocol::Binary, &client_tls)?;
+    client.request(&Request::SET(b"key1".to_vec(), b"value1".to_vec()))?;
+    assert_eq!(
+        client.request(&Request::GET(b"key1".to_vec()))?,
+        Some(b"value1".to_vec())
+    );
+
+    let anonymous = ClientTlsConfig::new(cert_dir.path().join("ca.pem"))?;
+    let other = ClientTlsConfig::with_client_cert(
+        cert_dir.path().join("ca.pem"),
+        cert_dir.path().join("other.pem"),
+        cert_dir.path().join("other.key"),
+    )?;
+    for tls in [anonymous, other] {
+        assert!(Client::connect_tls(addr, Protocol::Binary, &tls)
+            .and_then(|mut client| client.request(&Request::GET(b"key1".to_vec())))
+            .is_err());
+    }
+
+    drop(client);
+    stop_server(shutdown, handle);
+    Ok(())
+}
+
+// Connections should only access the keys their user is granted, with the anonymous
+// permissions until they authenticate
+#[test]
+fn access_control() -> Result<()> {
+    let addr = "127.0.0.1:4109";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut acl = AccessControl::default();
+    acl.anonymous.read = vec!["public/".to_owned()];
+    let app = Permissions {
+        read: vec!["app/".to_owned(), "public/".to_owned()],
+        write: vec!["app/".to_owned()],
+        admin: false,
+    };
+    acl.set_user("alice", "alice-secret", app)?;
+    let admin = Permissions {
+        read: vec!["".to_owned()],
+        write: vec!["".to_owned()],
+        admin: true,
+    };
+    acl.set_user("root", "root-secret", admin)?;
+    let (shutdown, handle) = run_server(addr, new_server(&temp_dir).access_control(acl));
+
+    let denied =
+        |result: Result<Option<Vec<u8>>>| matches!(result, Err(KVStoreError::PermissionDenied(_)));
+
+    let mut root = Client::new(addr)?;
+    assert!(denied(
+        root.request(&Request::SET(b"public/1".to_vec(), b"1".to_vec()))
+    ));
+    assert!(root.authenticate("root", "alice-secret").is_err());
+    assert!(root.authenticate("nobody", "root-secret").is_err());
+    root.authenticate("root", "root-secret")?;
+    root.request(&Request::SET(b"public/1".to_vec(), b"1".to_vec()))?;
+    root.request(&Request::SET(b"private/1".to_vec(), b"1".to_vec()))?;
+    root.request(&Request::COMPACT)?;
+
+    let mut anonymous = Client::connect(addr, Protocol::Json)?;
+    assert_eq!(
+        anonymous.request(&Request::GET(b"public/1".to_vec()))?,
+        Some(b"1".to_vec())
+    );
+    assert!(denied(
+        anonymous.request(&Request::GET(b"private/1".to_vec()))
+    ));
+
+    let mut alice = Client::new(addr)?;
+    alice.authenticate("alice", "alice-secret")?;
+    alice.request(&Request::SET(b"app/1".to_vec(), b"1".to_vec()))?;
+    assert!(denied(
+        alice.request(&Request::SET(b"public/2".to_vec(), b"2".to_vec()))
+    ));
+    assert!(denied(alice.request(&Request::RM(b"public/1".to_vec()))));
+    assert!(denied(alice.request(&Request::GET(b"private/1".to_vec()))));
+    assert!(denied(alice.request(&Request::CAS(
+        b"private/1".to_vec(),
+        None,
+        None
+    ))));
+    assert!(denied(alice.request(&Request::COMPACT)));
+    assert!(denied(
+        alice.request(&Request::BACKUP("snapshot".to_owned()))
+    ));
+    assert_eq!(alice.scan_prefix("app/", None)?.len(), 1);
+    assert!(matches!(
+        alice.scan("", None, None),
+        Err(KVStoreError::PermissionDenied(_))
+    ));
+    assert!(matches!(
+        alice.scan("app/", Some(b"private/".to_vec()), None),
+        Err(KVStoreError::PermissionDenied(_))
+    ));
+
+    let mut batch = WriteBatch::new();
+    batch.set(b"app/2".to_vec(), b"2".to_vec());
+    batch.set(b"private/2".to_vec(), b"2".to_vec());
+    assert!(denied(alice.request(&Request::BATCH(batch))));
+    assert_eq!(alice.request(&Request::GET(b"app/2".to_vec()))?, None);
+
+    // a batch holding anything but sets and removals is refused before its keys are checked,
+    // so a read-only connection cannot slip a batch header into the store
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(br#"{"BATCH":[{"BATCH":2}]}"#)?;
+    let mut received = Vec::new();
+    stream.read_to_end(&mut received)?;
+    assert!(received.is_empty());
+    root.request(&Request::SET(b"private/2".to_vec(), b"2".to_vec()))?;
+    assert_eq!(
+        root.request(&Request::GET(b"private/2".to_vec()))?,
+        Some(b"2".to_vec())
+    );
+
+    // a failed authentication drops the permissions of an earlier one
+    assert!(root.authenticate("alice", "root-secret").is_err());
+    assert!(denied(
+        root.request(&Request::SET(b"private/3".to_vec(), b"3".to_vec()))
+    ));
+    assert!(denied(root.request(&Request::GET(b"private/1".to_vec()))));
+    assert_eq!(
+        root.request(&Request::GET(b"public/1".to_vec()))?,
+        Some(b"1".to_vec())
+    );
+
+    drop(root);
+    drop(anonymous);
+    drop(alice);
+    stop_server(shutdown, handle);
+    Ok(())
+}